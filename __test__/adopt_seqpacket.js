@@ -0,0 +1,9 @@
+const { SeqpacketSocket } = require('../js')
+const { parentPort } = require('worker_threads')
+
+parentPort.once('message', (fd) => {
+  const socket = new SeqpacketSocket(fd)
+  socket.on('data', (buf) => {
+    socket.write(Buffer.from(`worker: ${buf.toString()}`))
+  })
+})
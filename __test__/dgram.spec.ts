@@ -2,6 +2,8 @@ import * as path from 'path';
 import * as fs from 'fs';
 import * as os from 'os';
 import * as workerThreads from 'worker_threads'
+import * as childProcess from 'child_process'
+import { AsyncLocalStorage } from 'async_hooks'
 import { DgramSocket } from '../js/index';
 import { kTmp, silently, createDefer, kServerPath, wait } from './util';
 
@@ -126,6 +128,82 @@ describe('DgramSocket', () => {
     client.close();
   });
 
+  it('should call callbacks of sendTo() exactly once', async () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    const client = new DgramSocket();
+    const times = 1000;
+    const results: (string | null)[] = [];
+
+    // the server can't receive until we yield so that messages get queued
+    for (let i = 0; i < times; i += 1) {
+      client.sendTo(Buffer.from('hello'), 0, 5, kServerPath, (err) => {
+        results.push(err ? (err as any).code : null);
+      });
+    }
+    const sent = results.length;
+    expect(sent).toBeLessThan(times);
+    expect(results.every((r) => r === null)).toBe(true);
+
+    const { p, resolve } = createDefer();
+    client.on('close', () => resolve());
+    client.close();
+    expect(results.length).toBe(times);
+    expect(results.slice(sent).every((r) => r === 'ECANCELED')).toBe(true);
+    await p;
+
+    server.close();
+  });
+
+  it('should call every cancelled callback and emit "close" even if callbacks throw', () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    const client = new DgramSocket();
+    const times = 1000;
+    const results: (string | null)[] = [];
+    let closed = false;
+    client.on('close', () => {
+      closed = true;
+    });
+
+    // the server can't receive until we yield so that messages get queued
+    for (let i = 0; i < times; i += 1) {
+      client.sendTo(Buffer.from('hello'), 0, 5, kServerPath, (err) => {
+        results.push(err ? (err as any).code : null);
+        if (err) {
+          throw new Error(`cb ${i}`);
+        }
+      });
+    }
+    const sent = results.length;
+
+    expect(() => client.close()).toThrow(`cb ${sent}`);
+    expect(results.length).toBe(times);
+    expect(results.slice(sent).every((r) => r === 'ECANCELED')).toBe(true);
+    expect(closed).toBe(true);
+
+    server.close();
+  });
+
+  it('should throw errors that we throw in callbacks called inside sendTo()', async () => {
+    const { p, resolve } = createDefer<Buffer>();
+    const server = new DgramSocket();
+    server.on('data', (buf) => resolve(buf));
+    server.bind(kServerPath);
+    const client = new DgramSocket();
+
+    // the message is sent right away so that the callback is called inside
+    // sendTo()
+    const buf = Buffer.from('hello');
+    expect(() => client.sendTo(buf, 0, buf.length, kServerPath, () => {
+      throw new Error('my_error');
+    })).toThrow('my_error');
+    expect((await p).toString()).toBe('hello');
+
+    client.close();
+    server.close();
+  });
+
   it('should throw when trying to bind a path that is too long', async () => {
     const socket = new DgramSocket();
 
@@ -175,6 +253,76 @@ describe('DgramSocket', () => {
     client.sendTo(buf, 0, buf.length, kServerPath, afterSend);
   });
 
+  it('should be safe to send and close sockets in callbacks of sendTo()', async () => {
+    for (let i = 0; i < 10; i += 1) {
+      const server = new DgramSocket();
+      server.bind(kServerPath);
+      const client = new DgramSocket();
+      const { p, resolve } = createDefer();
+      const times = 1000;
+      let sent = 0;
+      let closed = false;
+
+      client.on('close', () => resolve());
+      for (let j = 0; j < times * 2 && !closed; j += 1) {
+        client.sendTo(Buffer.from('hello'), 0, 5, kServerPath, (err) => {
+          if (err) {
+            return;
+          }
+          sent += 1;
+          if (sent === times) {
+            closed = true;
+            client.close();
+          }
+        });
+      }
+
+      await p;
+      expect(sent).toBe(times);
+      expect(() => client.sendTo(Buffer.from('hello'), 0, 5, kServerPath)).toThrow('closed');
+
+      server.close();
+      silently(() => fs.unlinkSync(kServerPath));
+    }
+  });
+
+  it('should send in callbacks of sendTo() without growing the stack', async () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    const client = new DgramSocket();
+    const { p, resolve } = createDefer();
+    const times = 100000;
+    let received = 0;
+    let sent = 0;
+
+    server.on('data', () => {
+      received += 1;
+      if (received === times) {
+        resolve();
+      }
+    });
+    const next = () => {
+      sent += 1;
+      if (sent <= times) {
+        client.sendTo(Buffer.from('a'), 0, 1, kServerPath, next);
+      }
+    };
+    next();
+
+    await p;
+    expect(client.stats().msgsSent).toBe(times);
+
+    // the socket keeps receiving after it has been waiting to be writable
+    const { p: pData, resolve: resolveData } = createDefer();
+    client.bind(kClientPath);
+    client.on('data', () => resolveData());
+    server.sendTo(Buffer.from('b'), 0, 1, kClientPath);
+    await pData;
+
+    client.close();
+    server.close();
+  });
+
   it('should return remote path correctly', async () => {
     let resolve;
 
@@ -253,6 +401,70 @@ describe('DgramSocket', () => {
     server.close();
   });
 
+  it('should send and receive zero-length messages', async () => {
+    const { p, resolve } = createDefer<Buffer>();
+
+    const client = new DgramSocket();
+    const server = new DgramSocket();
+    server.on('data', (buf) => {
+      resolve(buf);
+    });
+    server.bind(kServerPath);
+
+    client.sendTo(Buffer.alloc(0), 0, 0, kServerPath, emptyFn);
+    expect(() => client.sendTo(Buffer.alloc(4), 2, 4, kServerPath)).toThrow('out of the buffer');
+
+    expect((await p).length).toBe(0);
+
+    client.close();
+    server.close();
+  });
+
+  it('should send several buffers as one datagram with sendvTo()', async () => {
+    const received: string[] = [];
+    const { p, resolve } = createDefer();
+
+    const client = new DgramSocket();
+    const server = new DgramSocket();
+    server.on('data', (buf) => {
+      received.push(buf.toString());
+      if (received.length === 2) {
+        resolve();
+      }
+    });
+    server.bind(kServerPath);
+
+    const { p: pSend, resolve: resolveSend } = createDefer<Error | null>();
+    client.sendvTo([Buffer.from('head:'), Buffer.alloc(0), Buffer.from('body')], kServerPath, (err) => resolveSend(err));
+    client.sendvTo([], kServerPath);
+
+    expect(await pSend).toBe(null);
+    await p;
+    expect(received).toEqual(['head:body', '']);
+
+    client.close();
+    server.close();
+  });
+
+  it('should send the "length" bytes from "offset"', async () => {
+    const { p, resolve } = createDefer<Buffer>();
+
+    const client = new DgramSocket();
+    const server = new DgramSocket();
+    server.on('data', (buf) => {
+      resolve(buf);
+    });
+    server.bind(kServerPath);
+
+    const buf = Buffer.from('0123456789');
+    client.sendTo(buf, 3, 4, kServerPath, emptyFn);
+
+    expect((await p).toString()).toBe('3456');
+
+    client.close();
+    server.close();
+  });
+
   it('should allow to send a long msg (although the msg might get dropped)', async () => {
     const { p, resolve } = createDefer<Buffer>();
 
@@ -283,6 +495,82 @@ describe('DgramSocket', () => {
     expect(() => server.address()).toThrow();
   });
 
+  it('should replace the socket file atomically if "atomic" is set', async () => {
+    const oldServer = new DgramSocket();
+    oldServer.bind(kServerPath);
+    oldServer.on('data', () => {
+      throw new Error('unexpected data');
+    });
+
+    const server = new DgramSocket();
+    server.bind(kServerPath, { atomic: true });
+    expect(server.address()).toBe(kServerPath);
+
+    const { p, resolve } = createDefer<Buffer>();
+    server.on('data', (buf) => {
+      resolve(buf);
+    });
+
+    const client = new DgramSocket();
+    const buf = Buffer.from('hello');
+    client.sendTo(buf, 0, buf.length, kServerPath);
+
+    const received = await p;
+    expect(received.toString()).toBe('hello');
+    const files = fs.readdirSync(kTmp);
+    expect(files.filter((file) => file.endsWith('.tmp'))).toEqual([]);
+
+    client.close();
+    server.close();
+    oldServer.close();
+  });
+
+  it('should bind atomically to paths that only fit without the temporary suffix', async () => {
+    // the longest path that fits sun_path, which has 108 bytes on Linux
+    const maxLen = os.platform() === 'darwin' ? 103 : 107;
+    const name = 'a'.repeat(maxLen - kTmp.length - 1);
+    const longPath = path.resolve(kTmp, name);
+    silently(() => fs.unlinkSync(longPath));
+
+    const server = new DgramSocket();
+    server.bind(longPath, { atomic: true });
+    expect(fs.statSync(longPath).isSocket()).toBe(true);
+    const files = fs.readdirSync(kTmp);
+    expect(files.filter((file) => file.endsWith('.tmp'))).toEqual([]);
+
+    server.close();
+    fs.unlinkSync(longPath);
+  });
+
+  it('should hold the lock file if "lock" is set', () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath, { lock: true });
+
+    const another = new DgramSocket();
+    expect(() => another.bind(kServerPath, { lock: true })).toThrow(
+      'is locked by another process'
+    );
+
+    // the socket file is stale after closing
+    server.close();
+    expect(fs.existsSync(kServerPath)).toBe(true);
+    expect(() => another.bind(kServerPath)).toThrow();
+
+    another.bind(kServerPath, { lock: true });
+    expect(another.address()).toBe(kServerPath);
+    another.close();
+  });
+
+  if (os.platform() === 'linux') {
+    it('should reject "lock" for abstract socket addresses', () => {
+      const socket = new DgramSocket();
+      expect(() => socket.bind('\0node-unix-socket-lock', { lock: true })).toThrow(
+        "locking doesn't support abstract socket addresses"
+      );
+      socket.close();
+    });
+  }
+
   it('shoud set/send recv/send buffer size', () => {
     const server = new DgramSocket();
 
@@ -299,6 +587,350 @@ describe('DgramSocket', () => {
     server.close();
   });
 
+  it('should get and set socket options', () => {
+    const socket = new DgramSocket();
+
+    socket.setSockOpt('SO_RCVBUF', 10000);
+    expect(socket.getSockOpt('SO_RCVBUF')).toBe(socket.getRecvBufferSize());
+    socket.setSockOpt('SO_SNDTIMEO', 1500);
+    expect(socket.getSockOpt('SO_SNDTIMEO')).toBe(1500);
+
+    const value = Buffer.alloc(4);
+    value.writeInt32LE(1);
+    // SOL_SOCKET, SO_BROADCAST
+    const [level, optname] = os.platform() === 'linux' ? [1, 6] : [0xffff, 0x20];
+    socket.setRawSockOpt(level, optname, value);
+    expect(socket.getSockOpt('SO_BROADCAST')).toBe(true);
+    expect(socket.getRawSockOpt(level, optname, 4).readInt32LE()).not.toBe(0);
+
+    socket.close();
+  });
+
+  it('should run events and callbacks in the async context where sockets get created', async () => {
+    const storage = new AsyncLocalStorage<string>();
+    const stores: Record<string, string | undefined> = {};
+    const { p, resolve } = createDefer();
+
+    const server = storage.run('server', () => new DgramSocket());
+    server.bind(kServerPath);
+    server.on('data', () => {
+      stores.data = storage.getStore();
+      resolve();
+    });
+    const client = storage.run('client', () => new DgramSocket());
+
+    // some messages are queued and sent in later iterations of the loop
+    for (let i = 0; i < 20; i += 1) {
+      client.sendTo(Buffer.from('hello'), 0, 5, kServerPath, () => {
+        stores.sendTo = storage.getStore();
+      });
+    }
+
+    await p;
+    client.close();
+    expect(stores).toEqual({ data: 'server', sendTo: 'client' });
+
+    server.close();
+  });
+
+  it('should report kernel receive timestamps if they are enabled', async () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    if (os.platform() !== 'linux') {
+      expect(() => server.setRecvTimestamp(true)).toThrow('not supported');
+      server.close();
+      return;
+    }
+    server.setRecvTimestamp(true);
+    expect(server.getRecvTimestamp()).toBe(true);
+    const client = new DgramSocket();
+    const { p, resolve } = createDefer<any[]>();
+    server.on('data', (...args) => resolve(args));
+
+    const before = Date.now();
+    client.sendTo(Buffer.from('hello'), 0, 5, kServerPath, emptyFn);
+    const [buf, , timestamp] = await p;
+
+    expect(buf.toString()).toBe('hello');
+    const [sec, nsec] = timestamp;
+    expect(nsec).toBeGreaterThanOrEqual(0);
+    expect(nsec).toBeLessThan(1e9);
+    const ms = sec * 1e3 + nsec / 1e6;
+    expect(ms).toBeGreaterThanOrEqual(before - 1);
+    expect(ms).toBeLessThanOrEqual(Date.now());
+
+    client.close();
+    server.close();
+  });
+
+  it('should count messages in stats()', async () => {
+    const client = new DgramSocket();
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+
+    const { p, resolve } = createDefer();
+    server.on('data', () => resolve());
+    await new Promise<void>((resolve) => {
+      const buf = Buffer.from('hello');
+      client.sendTo(buf, 0, buf.length, kServerPath, () => resolve());
+    });
+    await new Promise<void>((resolve) => {
+      const buf = Buffer.from('hello');
+      client.sendTo(buf, 0, buf.length, kInvalidPath, () => resolve());
+    });
+    await p;
+
+    const clientStats = client.stats();
+    expect(clientStats.msgsSent).toBe(1);
+    expect(clientStats.bytesSent).toBe(5);
+    expect(clientStats.msgsDropped).toBe(1);
+    expect(clientStats.queueLength).toBe(0);
+
+    const serverStats = server.stats();
+    expect(serverStats.msgsReceived).toBe(1);
+    expect(serverStats.bytesReceived).toBe(5);
+
+    client.close();
+    server.close();
+  });
+
+  it('should report bytes queued in the kernel', async () => {
+    const client = new DgramSocket();
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    expect(server.getNextDatagramSize()).toBe(null);
+
+    const { p, resolve } = createDefer<(number | null)[]>();
+    server.once('data', () => {
+      // the second datagram is still in the kernel
+      resolve([server.getNextDatagramSize(), server.getUnreadBytes()]);
+    });
+    client.sendTo(Buffer.alloc(100), 0, 100, kServerPath, emptyFn);
+    client.sendTo(Buffer.alloc(30), 0, 30, kServerPath, emptyFn);
+
+    const [next, unread] = await p;
+    expect(next).toBe(30);
+    expect(unread).toBeGreaterThanOrEqual(30);
+    expect(client.getUnsentBytes()).toBeGreaterThanOrEqual(0);
+
+    client.close();
+    server.close();
+  });
+
+  it('should peek at datagrams without consuming them', async () => {
+    const client = new DgramSocket();
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    server.pause();
+    expect(server.peek()).toBe(null);
+
+    await new Promise<void>((resolve) => {
+      client.sendTo(Buffer.from('hello'), 0, 5, kServerPath, () => resolve());
+    });
+    await new Promise<void>((resolve) => {
+      client.sendTo(Buffer.from('world'), 0, 5, kServerPath, () => resolve());
+    });
+    await wait(10);
+
+    expect(server.peek()!.toString()).toBe('hello');
+    expect(server.peek(2)!.toString()).toBe('he');
+    if (os.platform() === 'linux') {
+      server.setSockOpt('SO_PEEK_OFF', 0);
+      expect(server.peek()!.toString()).toBe('hello');
+      expect(server.peek()!.toString()).toBe('world');
+      expect(server.peek()).toBe(null);
+      server.setSockOpt('SO_PEEK_OFF', -1);
+    }
+
+    const received: string[] = [];
+    const { p, resolve } = createDefer();
+    server.on('data', (buf) => {
+      received.push(buf.toString());
+      if (received.length === 2) {
+        resolve();
+      }
+    });
+    server.resume();
+    await p;
+    expect(received).toEqual(['hello', 'world']);
+
+    client.close();
+    server.close();
+  });
+
+  it('should send and receive synchronously', () => {
+    const client = new DgramSocket();
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    server.pause();
+
+    expect(() => server.recvSync(10)).toThrow('recvSync timed out');
+    client.sendSync(Buffer.from('hello'), 0, 5, kServerPath, 1000);
+    const msg = server.recvSync(1000);
+    expect(msg.buf.toString()).toBe('hello');
+    expect(msg.path).toBe('');
+    expect(() => client.sendSync(Buffer.from('hello'), 0, 5, kInvalidPath, 1000)).toThrow();
+
+    client.close();
+    server.close();
+  });
+
+  it('should send messages in "exit" listeners with sendSync() and flushSync()', () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    server.pause();
+
+    childProcess.spawnSync(process.execPath, [
+      path.resolve(__dirname, './send_on_exit.js'),
+      kServerPath,
+    ]);
+    const received: string[] = [];
+    for (let i = 0; i < 3; i += 1) {
+      received.push(server.recvSync(100).buf.toString());
+    }
+    expect(received).toEqual(['queued 0', 'queued 1', 'final']);
+    expect(() => server.recvSync(10)).toThrow('recvSync timed out');
+
+    server.close();
+  });
+
+  it('should send queued messages before closing with closeWhenFlushed()', async () => {
+    const client = new DgramSocket();
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    server.pause();
+
+    const results: (string | null)[] = [];
+    const count = 1000;
+    for (let i = 0; i < count; i += 1) {
+      const buf = Buffer.alloc(1024);
+      client.sendTo(buf, 0, buf.length, kServerPath, (err) => {
+        results.push(err ? (err as any).code : null);
+      });
+    }
+    expect(client.stats().queueLength).toBeGreaterThan(0);
+
+    const { p, resolve } = createDefer();
+    client.on('close', () => resolve());
+    client.closeWhenFlushed();
+    expect(() => client.sendTo(Buffer.from('hello'), 0, 5, kServerPath)).toThrow('closing');
+    let received = 0;
+    server.on('data', () => {
+      received += 1;
+    });
+    server.resume();
+    await p;
+
+    expect(results.length).toBe(count);
+    expect(results.every((err) => err === null)).toBe(true);
+    await wait(50);
+    expect(received).toBe(count);
+
+    server.close();
+  });
+
+  it('should close sockets after the timeout of closeWhenFlushed()', async () => {
+    const client = new DgramSocket();
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    server.pause();
+
+    const results: (string | null)[] = [];
+    for (let i = 0; i < 1000; i += 1) {
+      const buf = Buffer.alloc(1024);
+      client.sendTo(buf, 0, buf.length, kServerPath, (err) => {
+        results.push(err ? (err as any).code : null);
+      });
+    }
+
+    const { p, resolve } = createDefer();
+    client.on('close', () => resolve());
+    client.closeWhenFlushed(50);
+    await p;
+
+    expect(results.length).toBe(1000);
+    expect(results[results.length - 1]).toBe('ECANCELED');
+
+    server.close();
+  });
+
+  it('should adopt a detached fd', async () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    const fd = server.detach();
+    expect(() => server.detach()).toThrow('closed');
+
+    const adopted = new DgramSocket(fd);
+    expect(adopted.address()).toBe(kServerPath);
+    const { p, resolve } = createDefer<Buffer>();
+    adopted.on('data', (buf) => resolve(buf));
+
+    const client = new DgramSocket();
+    const buf = Buffer.from('hello');
+    client.sendTo(buf, 0, buf.length, kServerPath, emptyFn);
+    expect((await p).toString()).toBe('hello');
+
+    client.close();
+    adopted.close();
+  });
+
+  it('should refuse to detach sockets with messages waiting to be sent', () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    const client = new DgramSocket();
+
+    // the server can't receive until we yield so that messages get queued
+    for (let i = 0; i < 1000; i += 1) {
+      client.sendTo(Buffer.from('hello'), 0, 5, kServerPath, emptyFn);
+    }
+    expect(() => client.detach()).toThrow('pending messages');
+
+    // sending fails once the server is closed, which empties the queue
+    server.close();
+    client.flushSync();
+    const fd = client.detach();
+    new DgramSocket(fd).close();
+  });
+
+  it('should tear down workers where creating sockets fails', () => {
+    const ret = childProcess.spawnSync(process.execPath, [
+      path.resolve(__dirname, './worker_invalid_fd.js'),
+    ]);
+    expect(ret.status).toBe(0);
+    if (os.platform() !== 'darwin') {
+      expect(ret.stdout.toString()).toContain('EPERM');
+    }
+  });
+
+  it('should close fds of sockets that are garbage-collected', () => {
+    const ret = childProcess.spawnSync(process.execPath, [
+      '--expose-gc',
+      path.resolve(__dirname, './gc_sockets.js'),
+    ]);
+    expect(ret.stdout.toString()).toBe('0');
+    expect(ret.stderr.toString()).toContain('garbage-collected without being closed');
+  });
+
+  it('should only warn about unclosed sockets in environments that enable it', () => {
+    const ret = childProcess.spawnSync(process.execPath, [
+      '--expose-gc',
+      path.resolve(__dirname, './gc_sockets_worker.js'),
+    ]);
+    expect(ret.status).toBe(0);
+    expect(ret.stderr.toString()).not.toContain('garbage-collected without being closed');
+  });
+
+  it('should pass exceptions thrown in callbacks and listeners to "uncaughtException"', () => {
+    const ret = childProcess.spawnSync(process.execPath, [
+      path.resolve(__dirname, './uncaught_exceptions.js'),
+    ], { timeout: 10000 });
+    const caught = JSON.parse(ret.stdout.toString());
+    expect(caught.slice(0, 2)).toEqual(['sendTo 0', 'sendTo 1']);
+    if (os.platform() !== 'darwin') {
+      expect(caught.slice(2)).toEqual(['data a', 'data b', 'end 1']);
+    }
+  });
+
   it('should emit "close"', async () => {
     const client = new DgramSocket()
     const { p, resolve } = createDefer();
@@ -314,4 +946,75 @@ describe('DgramSocket', () => {
     await wait(1000)
     worker.terminate()
   });
+
+  it('should unlink paths of sockets when workers exit or get terminated', async () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+
+    for (let id = 10; id < 12; id += 1) {
+      const worker = new workerThreads.Worker(path.resolve(__dirname, './worker_sockets.js'), {
+        workerData: { id, mainPath: kServerPath, unlinkOnTeardown: true },
+      });
+      await new Promise<void>((resolve) => {
+        worker.once('message', () => resolve());
+      });
+      const dgramPath = path.resolve(kTmp, `./worker_dgram_${id}.sock`);
+      expect(fs.existsSync(dgramPath)).toBe(true);
+
+      const exited = new Promise<void>((resolve) => {
+        worker.once('exit', () => resolve());
+      });
+      if (id % 2 === 0) {
+        worker.terminate();
+      } else {
+        worker.postMessage('exit');
+      }
+      await exited;
+
+      expect(fs.existsSync(dgramPath)).toBe(false);
+      if (os.platform() !== 'darwin') {
+        expect(fs.existsSync(path.resolve(kTmp, `./worker_seqpacket_${id}.sock`))).toBe(false);
+      }
+    }
+
+    server.close();
+  });
+
+  it('should keep sockets of the main thread when workers exit or get terminated', async () => {
+    const server = new DgramSocket();
+    server.bind(kServerPath);
+    let received = 0;
+    server.on('data', () => {
+      received += 1;
+    });
+
+    for (let id = 0; id < 4; id += 1) {
+      const worker = new workerThreads.Worker(path.resolve(__dirname, './worker_sockets.js'), {
+        workerData: { id, mainPath: kServerPath },
+      });
+      await new Promise<void>((resolve) => {
+        worker.once('message', () => resolve());
+      });
+      const exited = new Promise<void>((resolve) => {
+        worker.once('exit', () => resolve());
+      });
+      if (id % 2 === 0) {
+        worker.terminate();
+      } else {
+        worker.postMessage('exit');
+      }
+      await exited;
+    }
+
+    const client = new DgramSocket();
+    const buf = Buffer.from('main');
+    await new Promise<void>((resolve) => {
+      client.sendTo(buf, 0, buf.length, kServerPath, () => resolve());
+    });
+    await wait(50);
+    expect(received).toBe(5);
+
+    client.close();
+    server.close();
+  });
 });
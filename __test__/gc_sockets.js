@@ -0,0 +1,28 @@
+const { DgramSocket, SeqpacketSocket, setUnclosedSocketWarning } = require('../js')
+const fs = require('fs')
+const os = require('os')
+
+const countFds = () => fs.readdirSync('/dev/fd').length
+
+setUnclosedSocketWarning(true)
+const before = countFds()
+
+;(() => {
+  for (let i = 0; i < 10; i += 1) {
+    new DgramSocket()
+    if (os.platform() !== 'darwin') {
+      new SeqpacketSocket()
+    }
+  }
+})()
+
+// finalizers run after gc
+setImmediate(() => {
+  global.gc()
+  setImmediate(() => {
+    global.gc()
+    setImmediate(() => {
+      process.stdout.write(String(countFds() - before))
+    })
+  })
+})
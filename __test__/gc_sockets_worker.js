@@ -0,0 +1,21 @@
+const { Worker, isMainThread } = require('worker_threads')
+const { DgramSocket, setUnclosedSocketWarning } = require('../js')
+
+// the warning enabled in the main thread doesn't apply to the worker
+if (isMainThread) {
+  setUnclosedSocketWarning(true)
+  new Worker(__filename)
+} else {
+  ;(() => {
+    for (let i = 0; i < 10; i += 1) {
+      new DgramSocket()
+    }
+  })()
+
+  setImmediate(() => {
+    global.gc()
+    setImmediate(() => {
+      global.gc()
+    })
+  })
+}
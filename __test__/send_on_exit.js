@@ -0,0 +1,15 @@
+const { DgramSocket } = require('../js')
+
+// send messages to the path in argv when the process exits, which can't be
+// done with sendTo() because the event loop doesn't run any more
+const serverPath = process.argv[2]
+const client = new DgramSocket()
+
+process.on('exit', () => {
+  for (let i = 0; i < 2; i += 1) {
+    client.sendTo(Buffer.from(`queued ${i}`), 0, 8, serverPath)
+  }
+  client.flushSync(1000)
+  client.sendSync(Buffer.from('final'), 0, 5, serverPath, 1000)
+  client.close()
+})
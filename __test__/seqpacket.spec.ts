@@ -1,6 +1,8 @@
 // TODO add tests for worker_threads
 import * as path from 'path';
 import * as fs from 'fs';
+import * as workerThreads from 'worker_threads';
+import { AsyncLocalStorage } from 'async_hooks';
 import { SeqpacketSocket, SeqpacketServer } from '../js/seqpacket';
 import { kTmp, silently, createDefer, kIsDarwin, wait } from './util';
 
@@ -284,6 +286,57 @@ if (!kIsDarwin) {
       client.destroy();
     });
 
+    it('should replace the socket file atomically if "atomic" is set', async () => {
+      const oldServer = new SeqpacketServer();
+      oldServer.listen(kServerpath);
+
+      const server = new SeqpacketServer();
+      server.listen(kServerpath, 511, { atomic: true });
+      expect(server.address()).toBe(kServerpath);
+
+      const { p, resolve } = createDefer<SeqpacketSocket>();
+      server.once('connection', (socket) => {
+        resolve(socket);
+      });
+      oldServer.on('connection', () => {
+        throw new Error('unexpected connection');
+      });
+
+      const client = new SeqpacketSocket();
+      client.connect(kServerpath);
+
+      const socket = await p;
+      const files = fs.readdirSync(kTmp);
+      expect(files.filter((file) => file.endsWith('.tmp'))).toEqual([]);
+
+      socket.destroy();
+      client.destroy();
+      server.close();
+      oldServer.close();
+    });
+
+    it('should fail fast if another server holds the lock', () => {
+      const server = new SeqpacketServer();
+      server.listen(kServerpath, 511, { lock: true });
+
+      const another = new SeqpacketServer();
+      expect(() => another.listen(kServerpath, 511, { lock: true })).toThrow(
+        'is locked by another process'
+      );
+
+      server.close();
+      another.listen(kServerpath, 511, { lock: true });
+      another.close();
+    });
+
+    it('should reject "lock" for abstract socket addresses', () => {
+      const server = new SeqpacketServer();
+      expect(() => server.listen('\0node-unix-socket-lock', 511, { lock: true })).toThrow(
+        "locking doesn't support abstract socket addresses"
+      );
+      server.close();
+    });
+
     it('should emit "_shutdown" and "end" event', async () => {
       await createTestPair(async (args) => {
         const { client, socket } = args;
@@ -314,14 +367,19 @@ if (!kIsDarwin) {
       });
     });
 
-    xit('should throw errors that we throw in callbacks of write()', async () => {
+    it('should throw errors that we throw in callbacks of write()', async () => {
       await createTestPair(async (args) => {
-        const { client } = args;
+        const { client, socket } = args;
+        const { p, resolve } = createDefer<Buffer>();
+        socket.on('data', (buf) => resolve(buf));
 
-        const buf = Buffer.alloc(5);
-        client.write(buf, 0, buf.length, () => {
+        // the message is written right away so that the callback is called
+        // inside write()
+        const buf = Buffer.from('hello');
+        expect(() => client.write(buf, 0, buf.length, () => {
           throw new Error('my_error');
-        });
+        })).toThrow('my_error');
+        expect((await p).toString()).toBe('hello');
       });
     });
 
@@ -516,6 +574,39 @@ if (!kIsDarwin) {
       });
     });
 
+    it('should count messages in stats()', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        socket.setInternalReadBufferSize(4);
+
+        const msgs = ['hello', 'hi', 'a'];
+        const { p, resolve } = createDefer();
+        let received = 0;
+        socket.on('data', () => {
+          received += 1;
+          if (received === msgs.length) {
+            resolve();
+          }
+        });
+        msgs.forEach(msg => client.write(Buffer.from(msg)));
+        await p;
+
+        const clientStats = client.stats();
+        expect(clientStats.msgsSent).toBe(3);
+        expect(clientStats.bytesSent).toBe(8);
+        expect(clientStats.queueLength).toBe(0);
+        expect(clientStats.queueBytes).toBe(0);
+        expect(clientStats.sendLatencyMax).toBeGreaterThanOrEqual(0);
+        expect(clientStats.sendLatencyTotal).toBeGreaterThanOrEqual(clientStats.sendLatencyMax);
+
+        const socketStats = socket.stats();
+        expect(socketStats.msgsReceived).toBe(3);
+        expect(socketStats.bytesReceived).toBe(7);
+        expect(socketStats.msgsTruncated).toBe(1);
+        expect(socketStats.msgsSent).toBe(0);
+      });
+    });
+
     it('should receive messages in order and keep messages length', async () => {
       await createTestPair(async (args) => {
         const { client, socket } = args;
@@ -564,6 +655,73 @@ if (!kIsDarwin) {
       });
     });
 
+    it('should get and set socket options', async () => {
+      await createTestPair(async (args) => {
+        const { client, server } = args;
+
+        client.setSockOpt('SO_SNDBUF', 65536)
+        expect(client.getSockOpt('SO_SNDBUF')).toBe(65536 * 2)
+        client.setSockOpt('SO_RCVTIMEO', 2000)
+        expect(client.getSockOpt('SO_RCVTIMEO')).toBe(2000)
+        client.setSockOpt('SO_LINGER', { enabled: true, seconds: 3 })
+        expect(client.getSockOpt('SO_LINGER')).toEqual({ enabled: true, seconds: 3 })
+        expect(server.getSockOpt('SO_ACCEPTCONN')).toBe(true)
+
+        const value = Buffer.alloc(4)
+        value.writeInt32LE(1)
+        // SOL_SOCKET, SO_PASSCRED
+        client.setRawSockOpt(1, 16, value)
+        expect(client.getRawSockOpt(1, 16, 4).readInt32LE()).toBe(1)
+        expect(client.getSockOpt('SO_PASSCRED')).toBe(true)
+
+        expect(() => client.getSockOpt('SO_UNKNOWN')).toThrow('unknown socket option')
+        expect(() => client.setSockOpt('SO_SNDBUF', true)).toThrow('expect a number')
+      });
+    });
+
+    it('should report bytes queued in the kernel', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+
+        const { p, resolve } = createDefer<number[]>();
+        socket.once('data', () => {
+          // the second message is still in the kernel
+          resolve([socket.getUnreadBytes(), client.getUnsentBytes()]);
+        });
+        client.write(Buffer.alloc(100));
+        client.write(Buffer.alloc(50));
+
+        const [unread, unsent] = await p;
+        expect(unread).toBe(50);
+        expect(unsent).toBeGreaterThanOrEqual(50);
+      });
+    });
+
+    it('should move connections to workers with detach()', async () => {
+      const worker = new workerThreads.Worker(path.resolve(__dirname, './adopt_seqpacket.js'));
+      const server = new SeqpacketServer();
+      server.listen(kServerpath);
+      server.on('connection', (socket) => {
+        const fd = socket.detach();
+        expect(() => socket.detach()).toThrow('destroyed');
+        worker.postMessage(fd);
+      });
+
+      const client = new SeqpacketSocket();
+      const { p, resolve } = createDefer<Buffer>();
+      client.on('data', (buf) => resolve(buf));
+      client.connect(kServerpath, () => {
+        client.write(Buffer.from('hello'));
+      });
+
+      const buf = await p;
+      expect(buf.toString()).toBe('worker: hello');
+
+      client.destroy();
+      server.close();
+      await worker.terminate();
+    });
+
     it('should write whole buffer if "offset" and "length" are missed', async () => {
       await createTestPair(async (args) => {
         const { client, socket } = args;
@@ -579,6 +737,32 @@ if (!kIsDarwin) {
       });
     });
 
+    it('should write several buffers as one message with writev()', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        const received: string[] = [];
+        const { p, resolve } = createDefer();
+        socket.on('data', (buf) => {
+          received.push(buf.toString());
+          if (received.length === 2) {
+            resolve();
+          }
+        });
+
+        const { p: pWrite, resolve: resolveWrite } = createDefer<Error | null>();
+        client.writev([Buffer.from('head:'), Buffer.from('body')], (err) => resolveWrite(err));
+        client.write(Buffer.from('next'));
+        const max = client.getMaxMsgSize();
+        expect(() => client.writev([Buffer.alloc(max), Buffer.alloc(1)])).toThrow(
+          expect.objectContaining({ code: 'EMSGSIZE' })
+        );
+
+        expect(await pWrite).toBe(null);
+        await p;
+        expect(received).toEqual(['head:body', 'next']);
+      });
+    });
+
     it('should emit "close" in sockets automatically when both read and write side of sockets are end', async () => {
       await createTestPair(async (args) => {
         const { client, socket, server } = args;
@@ -605,6 +789,427 @@ if (!kIsDarwin) {
       })
     });
 
+    it('should stop emitting "data" once sockets get destroyed in listeners', async () => {
+      for (let i = 0; i < 20; i += 1) {
+        await createTestPair(async (args) => {
+          const { client, socket } = args;
+          const { p, resolve } = createDefer();
+          let received = 0;
+
+          socket.on('close', () => resolve());
+          socket.on('data', () => {
+            received += 1;
+            if (received === 3) {
+              socket.destroy();
+            }
+          });
+          for (let j = 0; j < 20; j += 1) {
+            client.write(Buffer.from(`msg${j}`));
+          }
+
+          await p;
+          expect(received).toBe(3);
+        });
+      }
+    });
+
+    it('should be safe to write and destroy sockets in callbacks', async () => {
+      for (let i = 0; i < 20; i += 1) {
+        await createTestPair(async (args) => {
+          const { client, socket } = args;
+          const { p, resolve } = createDefer();
+          const times = 10;
+          let received = 0;
+          let written = 0;
+
+          socket.on('close', () => resolve());
+          socket.on('data', (buf) => {
+            received += 1;
+            socket.write(buf, 0, buf.length, () => {
+              written += 1;
+              if (written === times) {
+                socket.destroy();
+              }
+            });
+          });
+          for (let j = 0; j < times; j += 1) {
+            client.write(Buffer.from(`msg${j}`));
+          }
+
+          await p;
+          expect(received).toBe(times);
+        });
+      }
+    });
+
+    it('should write in callbacks of write() without growing the stack', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        const { p, resolve } = createDefer();
+        const times = 100000;
+        let received = 0;
+        let sent = 0;
+
+        socket.on('data', () => {
+          received += 1;
+          if (received === times) {
+            resolve();
+          }
+        });
+        const next = () => {
+          sent += 1;
+          if (sent <= times) {
+            client.write(Buffer.from('a'), 0, 1, next);
+          }
+        };
+        next();
+
+        await p;
+        expect(client.stats().msgsSent).toBe(times);
+      });
+    });
+
+    it('should call callbacks of write() with ECANCELED if sockets get destroyed', async () => {
+      await createTestPair(async (args) => {
+        const { client } = args;
+        const times = 2000;
+        const results: (string | null)[] = [];
+
+        // the other side doesn't read until we yield so that messages get queued
+        for (let i = 0; i < times; i += 1) {
+          client.write(Buffer.alloc(4096), 0, 4096, (err) => {
+            results.push(err ? (err as any).code : null);
+          });
+        }
+        const written = results.length;
+        expect(written).toBeLessThan(times);
+        expect(results.every((r) => r === null)).toBe(true);
+
+        client.destroy();
+        expect(results.length).toBe(times);
+        expect(results.slice(written).every((r) => r === 'ECANCELED')).toBe(true);
+      });
+    });
+
+    it('should call every cancelled callback and emit "close" even if callbacks throw', async () => {
+      await createTestPair(async (args) => {
+        const { client } = args;
+        const results: (string | null)[] = [];
+        let closed = false;
+        client.on('close', () => {
+          closed = true;
+        });
+
+        for (let i = 0; i < 2000; i += 1) {
+          client.write(Buffer.alloc(4096), 0, 4096, (err) => {
+            results.push(err ? (err as any).code : null);
+            if (err) {
+              throw new Error(`cb ${i}`);
+            }
+          });
+        }
+        const written = results.length;
+
+        expect(() => client.destroy()).toThrow(`cb ${written}`);
+        expect(results.length).toBe(2000);
+        expect(results.slice(written).every((r) => r === 'ECANCELED')).toBe(true);
+        expect(closed).toBe(true);
+      });
+    });
+
+    it('should call callbacks of write() with errors if writing fails', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        const { p, resolve } = createDefer<Error | null>();
+
+        client.on('error', () => {});
+        socket.destroy();
+        client.write(Buffer.from('hello'), 0, 5, (err) => {
+          resolve(err);
+        });
+
+        const err = await p;
+        expect(err).toBeTruthy();
+        expect((err as any).code).toBe('EPIPE');
+      });
+    });
+
+    it('should end and close sockets automatically after "end" if allowHalfOpen is false', async () => {
+      silently(() => fs.unlinkSync(kServerpath));
+      const server = new SeqpacketServer({ allowHalfOpen: false });
+      server.listen(kServerpath);
+      const client = new SeqpacketSocket();
+      const { p: pServerClose, resolve: resolveServerClose } = createDefer();
+      const { p: pClientClose, resolve: resolveClientClose } = createDefer();
+      const received: string[] = [];
+
+      server.on('connection', (socket) => {
+        socket.on('end', () => {
+          // the final response is sent before the write side gets shut down
+          socket.write(Buffer.from('bye'));
+        });
+        socket.on('close', () => resolveServerClose());
+      });
+      client.on('data', (buf) => received.push(buf.toString()));
+      client.on('close', () => resolveClientClose());
+      client.connect(kServerpath, () => {
+        client.end();
+      });
+
+      await pServerClose;
+      await pClientClose;
+      expect(received).toEqual(['bye']);
+
+      server.close();
+    });
+
+    it('should peek at messages without consuming them', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        socket.pause();
+        expect(socket.peek()).toBe(null);
+
+        client.write(Buffer.from('hello'));
+        client.write(Buffer.from('world'));
+        await wait(10);
+
+        expect(socket.peek()!.toString()).toBe('hello');
+        expect(socket.peek(2)!.toString()).toBe('he');
+        socket.setSockOpt('SO_PEEK_OFF', 0);
+        expect(socket.peek()!.toString()).toBe('hello');
+        expect(socket.peek()!.toString()).toBe('world');
+        expect(socket.peek()).toBe(null);
+        socket.setSockOpt('SO_PEEK_OFF', -1);
+
+        const received: string[] = [];
+        const { p, resolve } = createDefer();
+        socket.on('data', (buf) => {
+          received.push(buf.toString());
+          if (received.length === 2) {
+            resolve();
+          }
+        });
+        socket.resume();
+        await p;
+        expect(received).toEqual(['hello', 'world']);
+      });
+    });
+
+    it('should write, flush and read synchronously', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        socket.pause();
+        expect(() => socket.recvSync(10)).toThrow('recvSync timed out');
+
+        const { p, resolve } = createDefer<Error | null>();
+        client.write(Buffer.from('hello'), 0, 5, (err) => resolve(err));
+        client.writeSync(Buffer.from('world'), 0, 5, 1000);
+        client.write(Buffer.from('!'));
+        client.flushSync(1000);
+        expect(await p).toBe(null);
+
+        expect(socket.recvSync(1000)!.toString()).toBe('hello');
+        expect(socket.recvSync(1000)!.toString()).toBe('world');
+        expect(socket.recvSync(1000)!.toString()).toBe('!');
+
+        client.end();
+        expect(socket.recvSync(1000)).toBe(null);
+        // the end is reported again instead of waiting for the timeout
+        expect(socket.recvSync(1000)).toBe(null);
+      });
+    });
+
+    it('should tell zero-length messages apart from "end" without binding unbound sockets', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        const { p, resolve } = createDefer<Buffer>();
+        client.on('data', (buf) => resolve(buf));
+
+        socket.write(Buffer.from('hello'), 0, 0);
+        expect((await p).length).toBe(0);
+        expect(client.address()).toBe('');
+        expect(client.getSockOpt('SO_PASSCRED')).toBe(false);
+        // SOL_SOCKET and SO_TIMESTAMPNS on Linux
+        expect(client.getRawSockOpt(1, 35, 4).readInt32LE(0)).toBe(1);
+      });
+    });
+
+    it('should read zero-length messages synchronously as empty buffers', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        socket.pause();
+
+        client.writeSync(Buffer.alloc(0), 0, 0, 1000);
+        client.end();
+
+        const buf = socket.recvSync(1000);
+        expect(Buffer.isBuffer(buf)).toBe(true);
+        expect(buf!.length).toBe(0);
+        expect(socket.recvSync(1000)).toBe(null);
+      });
+    });
+
+    it('should write queued messages before closing with closeWhenFlushed()', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        socket.pause();
+
+        const results: (Error | null)[] = [];
+        const count = 1000;
+        for (let i = 0; i < count; i += 1) {
+          const buf = Buffer.alloc(1024);
+          client.write(buf, 0, buf.length, (err) => results.push(err));
+        }
+
+        const { p: pClose, resolve: resolveClose } = createDefer();
+        const { p: pEnd, resolve: resolveEnd } = createDefer();
+        client.on('close', () => resolveClose());
+        client.closeWhenFlushed();
+        let received = 0;
+        socket.on('data', () => {
+          received += 1;
+        });
+        socket.on('end', () => resolveEnd());
+        socket.resume();
+        await pClose;
+        await pEnd;
+
+        expect(results.length).toBe(count);
+        expect(results.every((err) => err === null)).toBe(true);
+        expect(received).toBe(count);
+      });
+    });
+
+    it('should emit zero-length messages as "data" instead of "end"', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        const received: string[] = [];
+        const { p, resolve } = createDefer();
+        socket.on('data', (buf) => received.push(buf.toString()));
+        socket.on('end', () => resolve());
+
+        const { p: pWrite, resolve: resolveWrite } = createDefer<Error | null>();
+        client.write(Buffer.alloc(0), 0, 0, (err) => resolveWrite(err));
+        client.write(Buffer.from('hello'));
+        // empty messages sent right before shutting down aren't taken as the end
+        client.write(Buffer.alloc(0));
+        client.end();
+
+        expect(await pWrite).toBe(null);
+        await p;
+        expect(received).toEqual(['', 'hello', '']);
+      });
+    });
+
+    it('should send each write as one message and reject messages larger than getMaxMsgSize()', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        const max = client.getMaxMsgSize();
+        expect(max).toBeGreaterThan(0);
+        const tooLarge = expect.objectContaining({ code: 'EMSGSIZE' });
+        expect(() => client.write(Buffer.alloc(max + 1))).toThrow(tooLarge);
+        expect(() => client.writeSync(Buffer.alloc(max + 1))).toThrow(tooLarge);
+        expect(() => client.write(Buffer.alloc(max + 1))).toThrow('larger than the max message size');
+
+        socket.setInternalReadBufferSize(max + 1);
+        const { p, resolve } = createDefer<Buffer>();
+        socket.on('data', (buf) => resolve(buf));
+        client.write(Buffer.alloc(max, 1));
+        const buf = await p;
+        expect(buf.length).toBe(max);
+      });
+    });
+
+    it('should shut down the read side with shutdownRead()', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        const { p: pWrite, resolve: resolveWrite } = createDefer<Error | null>();
+        const { p: pClose, resolve: resolveClose } = createDefer();
+
+        socket.on('error', () => {});
+        client.on('data', () => {
+          throw new Error('unexpected data');
+        });
+        client.on('close', () => resolveClose());
+
+        client.shutdownRead();
+        socket.write(Buffer.from('hello'), 0, 5, (err) => resolveWrite(err));
+        const err = await pWrite;
+        expect((err as any).code).toBe('EPIPE');
+
+        // closes once both sides are shut down
+        client.end();
+        await pClose;
+      });
+    });
+
+    it('should report kernel receive timestamps if they are enabled', async () => {
+      await createTestPair(async (args) => {
+        const { client, socket } = args;
+        const { p, resolve } = createDefer<any[]>();
+
+        socket.on('data', (...args) => resolve(args));
+        expect(socket.getRecvTimestamp()).toBe(false);
+        socket.setRecvTimestamp(true);
+
+        const before = Date.now();
+        client.write(Buffer.from('hello'));
+        const [buf, timestamp] = await p;
+
+        expect(buf.toString()).toBe('hello');
+        const [sec, nsec] = timestamp;
+        expect(nsec).toBeGreaterThanOrEqual(0);
+        expect(nsec).toBeLessThan(1e9);
+        const ms = sec * 1e3 + nsec / 1e6;
+        expect(ms).toBeGreaterThanOrEqual(before - 1);
+        expect(ms).toBeLessThanOrEqual(Date.now());
+      });
+    });
+
+    it('should run events and callbacks in the async context where sockets get created', async () => {
+      const storage = new AsyncLocalStorage<string>();
+      const stores: Record<string, string | undefined> = {};
+      const { p, resolve } = createDefer<SeqpacketSocket>();
+
+      const server = storage.run('server', () => new SeqpacketServer());
+      server.listen(kServerpath);
+      server.on('connection', (socket) => {
+        stores.connection = storage.getStore();
+        socket.on('data', (buf) => {
+          socket.write(buf);
+        });
+      });
+
+      storage.run('client', () => {
+        const client = new SeqpacketSocket();
+        client.connect(kServerpath, () => {
+          stores.connect = storage.getStore();
+          client.write(Buffer.from('hello'), 0, 5, () => {
+            stores.write = storage.getStore();
+          });
+        });
+        client.on('data', () => {
+          stores.data = storage.getStore();
+          process.nextTick(() => {
+            stores.tick = storage.getStore();
+            resolve(client);
+          });
+        });
+      });
+
+      const client = await p;
+      expect(stores).toEqual({
+        connection: 'server',
+        connect: 'client',
+        write: 'client',
+        data: 'client',
+        tick: 'client',
+      });
+
+      client.destroy();
+      server.close();
+    });
+
     it('should emit "error" and "close" in sockets when we write after remote sockets closed', async () => {
       await createTestPair(async (args) => {
         const { client, server, socket } = args;
@@ -1,6 +1,17 @@
 import * as net from 'net'
-import { createReuseportFd as createFd, closeFd } from '../js/index'
-import { hasIPv6 } from './util'
+import * as dgram from 'dgram'
+import {
+  createReuseportFd as createFd,
+  createReuseportUdpFd,
+  createReuseportListener,
+  ReuseportListener,
+  closeFd,
+  getSockOpt,
+  setSockOpt,
+  getRawSockOpt,
+  setRawSockOpt,
+} from '../js/index'
+import { hasIPv6, kIsDarwin } from './util'
 
 describe('tcp', () => {
   describe('createFd', () => {
@@ -57,6 +68,104 @@ describe('tcp', () => {
       servers.forEach(server => server.close());
     })
 
+    if (!kIsDarwin) {
+      it('should steer connections with a reuseport program', async () => {
+        const host = '127.0.0.1'
+        let port = 0;
+        // ret #0
+        const program = [{ code: 0x06, jt: 0, jf: 0, k: 0 }]
+
+        const servers: net.Server[] = [];
+        for (let i = 0; i < 3; i += 1) {
+          const fd = createFd(port, host, i === 0 ? { program } : undefined);
+          const server = await new Promise<net.Server>((resolve, reject) => {
+            const server = net.createServer()
+            server.listen({ fd }, () => {
+              resolve(server)
+            })
+          })
+          port = (server.address() as any).port
+          servers.push(server);
+        }
+
+        const connected = servers.map(() => 0);
+        servers.forEach((server, index) => {
+          server.on('connection', (socket) => {
+            connected[index] += 1;
+            socket.destroy();
+          })
+        })
+
+        for (let i = 0; i < 5; i += 1) {
+          await new Promise<void>((resolve) => {
+            const socket = net.connect(port, host);
+            socket.on('close', () => resolve());
+          })
+        }
+
+        expect(connected).toEqual([5, 0, 0]);
+        servers.forEach(server => server.close());
+      })
+
+      it('should accept steering presets', () => {
+        const fd = createFd(0, '127.0.0.1', { steering: 'cpu', groupSize: 2, incomingCpu: 0 })
+        closeFd(fd)
+        const udpFd = createReuseportUdpFd(0, '127.0.0.1', { steering: 'hash', groupSize: 1 })
+        closeFd(udpFd)
+
+        expect(() => createFd(0, '127.0.0.1', { steering: 'unknown' })).toThrow('unexpected steering')
+        expect(() => createFd(0, '127.0.0.1', { steering: 'cpu' })).toThrow("'groupSize' is required by steering 'cpu'")
+        expect(() => createReuseportUdpFd(0, '127.0.0.1', { steering: 'hash' })).toThrow("'groupSize' is required by steering 'hash'")
+      })
+
+      it('should reject programs that don\'t fit sock_filter', () => {
+        const ret = { code: 0x06, jt: 0, jf: 0, k: 0 }
+        expect(() => createFd(0, '127.0.0.1', {
+          program: [{ code: 0x15, jt: 256, jf: 0, k: 0 }, ret],
+        })).toThrow("'jt' of instruction 0 is out of range")
+        expect(() => createFd(0, '127.0.0.1', {
+          program: [ret, { code: 0x10006, jt: 0, jf: 0, k: 0 }],
+        })).toThrow("'code' of instruction 1 is out of range")
+        expect(() => createFd(0, '127.0.0.1', {
+          program: new Array(4097).fill(ret),
+        })).toThrow('a program has at most 4096 instructions, got 4097')
+      })
+
+      it('should set socket options', async () => {
+        const fd = createFd(0, '127.0.0.1', {
+          freebind: true,
+          bindToDevice: 'lo',
+          deferAccept: 1,
+          fastOpen: 16,
+          listen: true,
+          backlog: 128,
+        })
+
+        const server = await new Promise<net.Server>((resolve) => {
+          const server = net.createServer()
+          server.listen({ fd }, () => resolve(server))
+        })
+        const { port } = server.address() as net.AddressInfo
+        await new Promise<void>((resolve) => {
+          const socket = net.connect(port, '127.0.0.1', () => {
+            socket.destroy()
+            resolve()
+          })
+        })
+        server.close()
+
+        expect(() => createFd(0, '127.0.0.1', { ipv6Only: true })).toThrow('only supported by IPv6')
+        expect(() => createReuseportUdpFd(0, '127.0.0.1', { listen: true })).toThrow('only supported by TCP')
+      })
+
+      if (hasIPv6()) {
+        it('should bind to ipv6 addresses with scope ids', () => {
+          const fd = createFd(0, 'fe80::1%lo', { ipv6Only: true, freebind: true })
+          closeFd(fd)
+        })
+      }
+    }
+
     if (hasIPv6()) {
       it('should work with ipv6', async () => {
         const host = '::1'
@@ -99,6 +208,158 @@ describe('tcp', () => {
     }
   })
 
+  describe('createReuseportUdpFd', () => {
+    it('should work', async () => {
+      const host = '127.0.0.1'
+      let port = 0;
+
+      async function createSocket(): Promise<dgram.Socket> {
+        const fd = createReuseportUdpFd(port, host);
+
+        const socket = await new Promise<dgram.Socket>((resolve, reject) => {
+          const socket = dgram.createSocket('udp4')
+
+          socket.bind({
+            fd,
+          }, () => {
+            resolve(socket)
+          })
+        })
+
+        port = socket.address().port
+
+        return socket
+      }
+
+      const sockets: dgram.Socket[] = [];
+      for (let i = 0; i < 3; i += 1) {
+        const socket = await createSocket()
+        sockets.push(socket);
+      }
+
+      const pList = sockets.map(socket => {
+        return new Promise<Buffer>((resolve, reject) => {
+          socket.once('message', buf => {
+            resolve(buf)
+          })
+        })
+      })
+
+      const buf = Buffer.from('hello');
+      const client = dgram.createSocket('udp4');
+      client.send(buf, port, host);
+
+      const ret = await Promise.race(pList);
+      expect(ret.toString()).toBe(buf.toString())
+
+      client.close();
+      sockets.forEach(socket => socket.close());
+    })
+  })
+
+  describe('createReuseportListener', () => {
+    function createListener(port: number, host: string): Promise<ReuseportListener> {
+      return new Promise((resolve, reject) => {
+        createReuseportListener(port, host, (err, listener) => {
+          if (err) {
+            reject(err)
+            return
+          }
+          resolve(listener as ReuseportListener)
+        })
+      })
+    }
+
+    it('should resolve hostnames and tell the bound port', async () => {
+      const listener = await createListener(0, 'localhost')
+      const { address, port } = listener.address()
+      expect(port).toBeGreaterThan(0)
+
+      const other = await createListener(port, address)
+      expect(other.address().port).toBe(port)
+      other.close()
+
+      const server = await new Promise<net.Server>((resolve) => {
+        const server = net.createServer()
+        server.listen({ fd: listener.handOff() }, () => resolve(server))
+      })
+      expect((server.address() as net.AddressInfo).port).toBe(port)
+      // the fd is owned by the server
+      listener.close()
+      expect((server.address() as net.AddressInfo).port).toBe(port)
+      server.close()
+    })
+
+    it('should only hand off the fd by handOff()', async () => {
+      const listener = await createListener(0, '127.0.0.1')
+      const fd = listener.fd
+      expect(listener.fd).toBe(fd)
+      listener.close()
+      expect(() => getSockOpt(fd, 'SO_REUSEPORT')).toThrow()
+    })
+
+    it('should listen() and close()', async () => {
+      const listener = await createListener(0, '127.0.0.1')
+      listener.listen(16)
+      const { port } = listener.address()
+
+      await new Promise<void>((resolve) => {
+        const socket = net.connect(port, '127.0.0.1', () => {
+          socket.destroy()
+          resolve()
+        })
+      })
+
+      listener.close()
+      listener.close()
+      expect(() => listener.address()).toThrow('listener closed')
+    })
+
+    it('should reject ports out of range', async () => {
+      await expect(createListener(70000, '127.0.0.1')).rejects.toThrow('port should be in 0 to 65535, got 70000')
+      expect(() => new ReuseportListener('ipv4', -1, '127.0.0.1')).toThrow('port should be in 0 to 65535')
+      expect(() => createFd(70000, '127.0.0.1')).toThrow('port should be in 0 to 65535, got 70000')
+    })
+
+    it('should emit errors of resolving', async () => {
+      await expect(createListener(0, 'no-such-host.invalid')).rejects.toThrow('EAI_NONAME')
+    })
+  })
+
+  describe('getSockOpt', () => {
+    it('should get and set socket options of fds', () => {
+      const fd = createFd(0, '127.0.0.1')
+      expect(getSockOpt(fd, 'SO_REUSEPORT')).toBe(true)
+
+      setSockOpt(fd, 'SO_KEEPALIVE', true)
+      expect(getSockOpt(fd, 'SO_KEEPALIVE')).toBe(true)
+      setSockOpt(fd, 'SO_LINGER', { enabled: false, seconds: 0 })
+      expect(getSockOpt(fd, 'SO_LINGER')).toEqual({ enabled: false, seconds: 0 })
+      expect(getRawSockOpt(fd, kIsDarwin ? 0xffff : 1, kIsDarwin ? 0x1008 : 3, 4).readInt32LE()).toBe(1)
+
+      closeFd(fd)
+    })
+
+    if (!kIsDarwin) {
+      it('should set raw options with empty values', () => {
+        const fd = createFd(0, '127.0.0.1')
+        // clear SO_BINDTODEVICE
+        setRawSockOpt(fd, 1, 25, Buffer.alloc(0))
+        expect(getRawSockOpt(fd, 1, 25, 16).length).toBe(0)
+        closeFd(fd)
+      })
+    }
+
+    it('should work with listeners', async () => {
+      const listener = new ReuseportListener('ipv4', 0, '127.0.0.1')
+      expect(listener.getSockOpt('SO_ACCEPTCONN')).toBe(false)
+      listener.listen()
+      expect(listener.getSockOpt('SO_ACCEPTCONN')).toBe(true)
+      listener.close()
+      expect(() => listener.getSockOpt('SO_TYPE')).toThrow('listener closed')
+    })
+  })
+
   describe('closeFd', () => {
     it('should work', async () => {
       const fd = createFd(0)
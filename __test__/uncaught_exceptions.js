@@ -0,0 +1,109 @@
+const { DgramSocket, SeqpacketSocket, SeqpacketServer } = require('../js')
+const fs = require('fs')
+const os = require('os')
+const path = require('path')
+
+const dir = fs.mkdtempSync(path.join(os.tmpdir(), 'uncaught-'))
+const caught = []
+
+process.on('uncaughtException', (err) => {
+  caught.push(err.message)
+})
+
+function testDgram(next) {
+  const serverPath = path.join(dir, 'dgram.sock')
+  const server = new DgramSocket()
+  server.bind(serverPath)
+  server.pause()
+  const client = new DgramSocket()
+  const buf = Buffer.alloc(1024)
+  // callbacks of messages sent right away throw to the caller so that
+  // messages are queued until the server reads
+  while (client.stats().queueLength === 0) {
+    client.sendTo(buf, 0, buf.length, serverPath)
+  }
+  let called = 0
+  for (let i = 0; i < 2; i += 1) {
+    client.sendTo(buf, 0, buf.length, serverPath, () => {
+      called += 1
+      if (called === 2) {
+        setImmediate(() => {
+          client.close()
+          server.close()
+          next()
+        })
+      }
+      throw new Error(`sendTo ${i}`)
+    })
+  }
+  server.resume()
+}
+
+function testSeqpacket(next) {
+  if (os.platform() === 'darwin') {
+    return next()
+  }
+
+  const serverPath = path.join(dir, 'seqpacket.sock')
+  const server = new SeqpacketServer()
+  server.listen(serverPath)
+  const client = new SeqpacketSocket()
+  server.on('connection', (socket) => {
+    let received = 0
+    socket.on('data', (buf) => {
+      received += 1
+      if (received === 2) {
+        setImmediate(() => {
+          socket.destroy()
+          client.destroy()
+          server.close()
+          next()
+        })
+      }
+      throw new Error(`data ${buf.toString()}`)
+    })
+  })
+  client.connect(serverPath, () => {
+    client.write(Buffer.from('a'))
+    client.write(Buffer.from('b'))
+  })
+}
+
+function testSeqpacketEnd(next) {
+  if (os.platform() === 'darwin') {
+    return next()
+  }
+
+  const serverPath = path.join(dir, 'seqpacket_end.sock')
+  // accepted sockets end and then close once they receive "end"
+  const server = new SeqpacketServer({ allowHalfOpen: false })
+  server.listen(serverPath)
+  const client = new SeqpacketSocket()
+  server.on('connection', (socket) => {
+    let ends = 0
+    socket.on('end', () => {
+      ends += 1
+      throw new Error(`end ${ends}`)
+    })
+    socket.on('close', () => {
+      // "end" would be emitted again if the socket kept reading
+      setTimeout(() => {
+        client.destroy()
+        server.close()
+        next()
+      }, 50)
+    })
+  })
+  client.connect(serverPath, () => {
+    client.end()
+  })
+}
+
+testDgram(() => {
+  testSeqpacket(() => {
+    testSeqpacketEnd(() => {
+      fs.rmSync(dir, { recursive: true, force: true })
+      process.stdout.write(JSON.stringify(caught))
+    })
+  })
+})
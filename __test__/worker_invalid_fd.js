@@ -0,0 +1,18 @@
+const { Worker, isMainThread, parentPort } = require('worker_threads')
+const fs = require('fs')
+const { DgramSocket } = require('../js')
+
+// a worker that fails to create a socket should still be torn down cleanly
+if (isMainThread) {
+  const worker = new Worker(__filename)
+  worker.on('message', (msg) => process.stdout.write(msg))
+} else {
+  // regular files can't be polled on Linux
+  const fd = fs.openSync(__filename, 'r')
+  try {
+    new DgramSocket(fd).close()
+    parentPort.postMessage('created')
+  } catch (e) {
+    parentPort.postMessage(e.message)
+  }
+}
@@ -0,0 +1,37 @@
+const { DgramSocket, SeqpacketServer, SeqpacketSocket } = require('../js')
+const fs = require('fs')
+const path = require('path')
+const os = require('os')
+const { parentPort, workerData } = require('worker_threads')
+
+const { id, mainPath, unlinkOnTeardown } = workerData
+const dgramPath = path.resolve(__dirname, `./.tmp/worker_dgram_${id}.sock`)
+const seqpacketPath = path.resolve(__dirname, `./.tmp/worker_seqpacket_${id}.sock`)
+for (const p of [dgramPath, seqpacketPath]) {
+  try {
+    fs.unlinkSync(p)
+  } catch (err) {
+    //
+  }
+}
+
+const socket = new DgramSocket()
+socket.bind(dgramPath, { unlinkOnTeardown })
+const closed = new DgramSocket()
+closed.close()
+
+if (os.platform() !== 'darwin') {
+  const server = new SeqpacketServer()
+  server.listen(seqpacketPath, 511, { unlinkOnTeardown })
+  const client = new SeqpacketSocket()
+  client.connect(seqpacketPath)
+}
+
+const buf = Buffer.from(String(id))
+socket.sendTo(buf, 0, buf.length, mainPath, () => {
+  parentPort.postMessage('ready')
+})
+
+parentPort.on('message', () => {
+  process.exit(0)
+})
@@ -3,36 +3,262 @@
 
 /* auto-generated by NAPI-RS */
 
-export function socketNewSoReuseportFd(domain: string, port: number, ip: string): number
+/** Options of binding a unix socket to a path. */
+export interface BindOptions {
+  /**
+   * Bind to a temporary sibling path and rename() it over the target path
+   * once the socket is ready.
+   */
+  atomic?: boolean
+  /**
+   * Take an flock() on "<path>.lock" and hold it until the socket gets
+   * closed. Binding fails if another process holds the lock.
+   */
+  lock?: boolean
+  /**
+   * Remove the socket file if the socket is still open when its environment,
+   * e.g. a worker, is torn down. The file is kept if it has been replaced by
+   * another socket.
+   */
+  unlinkOnTeardown?: boolean
+}
+/** An instruction of a classic BPF program, i.e. `struct sock_filter`. */
+export interface BpfInstruction {
+  code: number
+  jt: number
+  jf: number
+  k: number
+}
+/** Options of creating SO_REUSEPORT enabled sockets. */
+export interface ReuseportOptions {
+  /**
+   * Attach a built-in SO_ATTACH_REUSEPORT_CBPF program. "cpu" selects the
+   * socket by the CPU that receives the packet while "hash" selects it by
+   * the rx hash of the flow. TCP sockets are put into the listening state
+   * before attaching programs.
+   */
+  steering?: string
+  /**
+   * The number of sockets in the reuseport group. It's required by
+   * `steering` as the built-in programs return `index % groupSize`.
+   */
+  groupSize?: number
+  /**
+   * A custom classic BPF program attached with SO_ATTACH_REUSEPORT_CBPF. The
+   * program returns the index of the socket in the group.
+   */
+  program?: Array<BpfInstruction>
+  /** Set SO_INCOMING_CPU. */
+  incomingCpu?: number
+  /** Set IPV6_V6ONLY. Only for IPv6 sockets. */
+  ipv6Only?: boolean
+  /** Set IP_FREEBIND to bind to addresses that don't exist yet. */
+  freebind?: boolean
+  /** Set SO_BINDTODEVICE, e.g. "lo". */
+  bindToDevice?: string
+  /** Set TCP_DEFER_ACCEPT in seconds. Only for TCP sockets. */
+  deferAccept?: number
+  /** Set the queue length of TCP_FASTOPEN. Only for TCP sockets. */
+  fastOpen?: number
+  /** Call listen() on the socket. Only for TCP sockets. */
+  listen?: boolean
+  /** The backlog passed to listen(). Defaults to 511. */
+  backlog?: number
+}
+/** The address of an IP socket, like the `net.AddressInfo` of Node.js. */
+export interface AddressInfo {
+  address: string
+  family: string
+  port: number
+}
+/** The value of SO_LINGER. */
+export interface Linger {
+  enabled: boolean
+  /** The linger time in seconds. */
+  seconds: number
+}
+/** A snapshot of the counters of a socket. */
+export interface SocketStats {
+  msgsSent: number
+  bytesSent: number
+  msgsReceived: number
+  bytesReceived: number
+  /**
+   * The number of writes that failed with EAGAIN or EWOULDBLOCK and were
+   * retried later.
+   */
+  eagainRetries: number
+  /** The number of writes that failed with ENOBUFS and were retried later. */
+  enobufsRetries: number
+  /** Messages that were never sent because of errors or the socket closed. */
+  msgsDropped: number
+  /**
+   * Received messages that were truncated because the read buffer is not
+   * large enough.
+   */
+  msgsTruncated: number
+  /** The number of messages waiting to be sent. */
+  queueLength: number
+  /** The bytes of messages waiting to be sent. */
+  queueBytes: number
+  /**
+   * The sum of the time from enqueuing to sending of sent messages in
+   * milliseconds. Divide it by `msgsSent` to get the average.
+   */
+  sendLatencyTotal: number
+  /** The max time from enqueuing to sending of sent messages in milliseconds. */
+  sendLatencyMax: number
+}
+export function socketNewSoReuseportFd(domain: string, port: number, ip: string, options?: ReuseportOptions | undefined | null): number
+export function socketNewSoReuseportUdpFd(domain: string, port: number, ip: string, options?: ReuseportOptions | undefined | null): number
+/**
+ * Resolve `host` with uv_getaddrinfo(). `cb` is called with an error or an
+ * array of `{ address, family }`.
+ */
+export function socketLookup(host: string, cb: (...args: any[]) => any): void
 export function socketClose(fd: number): void
+/**
+ * Print a warning when a socket of the current environment is
+ * garbage-collected without being closed.
+ */
+export function socketSetUnclosedWarning(enabled: boolean): void
+export function socketGetSockOpt(fd: number, name: string): unknown
+export function socketSetSockOpt(fd: number, name: string, value: unknown): void
+export function socketGetRawSockOpt(fd: number, level: number, optname: number, length: number): Buffer
+export function socketSetRawSockOpt(fd: number, level: number, optname: number, value: Buffer): void
 export function initCleanupHook(): void
+/**
+ * A TCP socket with SO_REUSEPORT enabled. The fd is closed when the listener
+ * gets garbage collected unless it has been handed off by `hand_off()`.
+ */
+export class ReuseportListener {
+  constructor(domain: string, port: number, ip: string, options?: ReuseportOptions | undefined | null)
+  /**
+   * The fd of the listener. It's still owned by the listener, see
+   * `hand_off()`.
+   */
+  get fd(): number
+  /**
+   * Hand off the fd, e.g. to net.Server, so that it won't be closed by
+   * `close()` or when the listener gets garbage collected. Returns the fd.
+   */
+  handOff(): number
+  address(): AddressInfo
+  listen(backlog?: number | undefined | null): void
+  getSockOpt(name: string): unknown
+  setSockOpt(name: string, value: unknown): void
+  getRawSockOpt(level: number, optname: number, length: number): Buffer
+  setRawSockOpt(level: number, optname: number, value: Buffer): void
+  close(): void
+}
 export class SeqpacketSocketWrap {
   constructor(ee: object, fd?: number | undefined | null)
   init(thisObj: object): void
   state(): number
   close(): void
+  /**
+   * Stop polling and release the uv handle without closing the fd. Returns
+   * the fd so that it can be adopted by another wrap, e.g. in a worker.
+   */
+  detach(): number
   shutdownWrite(): void
+  /**
+   * Shut down the read side with SHUT_RD and stop reading. Messages that
+   * haven't been read are dropped and the other side fails to write.
+   */
+  shutdownRead(): void
+  setAllowHalfOpen(allowHalfOpen: boolean): void
   uvRefer(): void
   uvUnrefer(): void
   setReadBufSize(size: number): void
   getReadBufSize(): number
+  setRecvTimestamp(enabled: boolean): void
+  getRecvTimestamp(): boolean
+  stats(): SocketStats
+  getUnreadBytes(): number
+  getUnsentBytes(): number
+  getMaxMsgSize(): number
+  peek(size?: number | undefined | null): Buffer | null
+  getSockOpt(name: string): unknown
+  setSockOpt(name: string, value: unknown): void
+  getRawSockOpt(level: number, optname: number, length: number): Buffer
+  setRawSockOpt(level: number, optname: number, value: Buffer): void
   startRecv(): void
+  stopRecv(): void
   address(): string
-  listen(bindpath: string, backlog: number): void
+  listen(bindpath: string, backlog: number, options?: BindOptions | undefined | null): void
   connect(serverPath: string): void
   write(buf: Buffer, offset: number, length: number, cb?: (...args: any[]) => any | undefined | null): void
+  /** Write the buffers together as one message without concatenating them. */
+  writev(bufs: Array<Buffer>, cb?: (...args: any[]) => any | undefined | null): void
   shutdownWhenFlushed(): void
+  /**
+   * Write messages in the queue with poll() instead of the event loop, e.g.
+   * in "exit" listeners. Their callbacks are called before it returns and
+   * the first exception they throw is thrown.
+   */
+  flushSync(timeout?: number | undefined | null): void
+  /**
+   * Write a message with poll() instead of the event loop after the queue is
+   * flushed. The message is dropped if it times out.
+   */
+  writeSync(buf: Buffer, offset: number, length: number, timeout?: number | undefined | null): void
+  /**
+   * Read a message with poll() instead of the event loop. Returns `None` if
+   * the other side has ended and throws if it times out.
+   */
+  recvSync(timeout?: number | undefined | null): Buffer | null
 }
 export class DgramSocketWrap {
-  constructor(ee: object)
+  constructor(ee: object, fd?: number | undefined | null)
   init(thisObj: object): void
   startRecv(): void
-  bind(bindpath: string): void
+  stopRecv(): void
+  bind(bindpath: string, options?: BindOptions | undefined | null): void
   address(): string
   getRecvBufferSize(): number
   setRecvBufferSize(size: number): void
   getSendBufferSize(): number
   setSendBufferSize(size: number): void
+  setRecvTimestamp(enabled: boolean): void
+  getRecvTimestamp(): boolean
+  stats(): SocketStats
+  getUnreadBytes(): number
+  getUnsentBytes(): number
+  getNextDatagramSize(): number | null
+  peek(size?: number | undefined | null): Buffer | null
+  getSockOpt(name: string): unknown
+  setSockOpt(name: string, value: unknown): void
+  getRawSockOpt(level: number, optname: number, length: number): Buffer
+  setRawSockOpt(level: number, optname: number, value: Buffer): void
   sendTo(buf: Buffer, offset: number, length: number, path: string, cb?: (...args: any[]) => any | undefined | null): void
+  /** Send the buffers together as one datagram without concatenating them. */
+  sendvTo(bufs: Array<Buffer>, path: string, cb?: (...args: any[]) => any | undefined | null): void
+  /**
+   * Send messages in the queue with poll() instead of the event loop, e.g.
+   * in "exit" listeners. Their callbacks are called before it returns and
+   * the first exception they throw is thrown.
+   */
+  flushSync(timeout?: number | undefined | null): void
+  /**
+   * Send a message with poll() instead of the event loop after the queue is
+   * flushed. The message is dropped if it times out.
+   */
+  sendSync(buf: Buffer, offset: number, length: number, path: string, timeout?: number | undefined | null): void
+  /**
+   * Read a datagram with poll() instead of the event loop. Returns
+   * `{ buf, path }` and throws if it times out.
+   */
+  recvSync(timeout?: number | undefined | null): object
+  /**
+   * Stop polling and release the uv handle without closing the fd. Returns
+   * the fd so that it can be adopted by another wrap, e.g. in a worker.
+   */
+  detach(): number
+  /**
+   * Close the socket once messages in the queue are sent and their callbacks
+   * are called. Messages can't be sent after it.
+   */
+  closeWhenFlushed(): void
   close(): void
 }
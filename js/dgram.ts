@@ -1,10 +1,21 @@
 import { EventEmitter } from 'events';
 import {
-  DgramSocketWrap
+  DgramSocketWrap,
+  BindOptions,
+  SocketStats,
 } from './addon';
+import { SockOpts, SockOptValue, RecvTimestamp } from './socket';
 
 type FnRecv = (err: undefined | Error, buf: Buffer) => void;
-export type SendCb = (err: undefined | Error) => void;
+export type SendCb = (err: null | Error) => void;
+
+/**
+ * A datagram returned by {@link DgramSocket.recvSync}.
+ */
+export interface DgramMessage {
+  buf: Buffer;
+  path: string;
+}
 
 /**
  * DgramSocket is used to create a SOCK_DGRAM unix domain socket.
@@ -15,8 +26,9 @@ export type SendCb = (err: undefined | Error) => void;
  * ### Event: `'data'`
  * - buffer `Buffer`
  * - path `string`
+ * - timestamp `RecvTimestamp | null`
  *
- * Emitted when data is received. `path` indicates remote address information.
+ * Emitted when data is received. `path` indicates remote address information. `timestamp` is only passed if it's enabled by {@link setRecvTimestamp}.
  *
  * ### Event: `'error'`
  * - error `Error`
@@ -24,26 +36,39 @@ export type SendCb = (err: undefined | Error) => void;
  * Emitted when an error occurs.
  *
  * ### Event: `'close'`
- * The 'close' event is emitted after a socket is closed with close().
+ * The 'close' event is emitted after a socket is closed with close(), even if callbacks of {@link sendTo} cancelled by it throw.
  */
-export class DgramSocket extends EventEmitter {
+export class DgramSocket extends EventEmitter implements SockOpts {
   private closed: boolean = false;
+  private closing: boolean = false;
   private wrap: DgramSocketWrap;
+  private reading: boolean = false;
+  private paused: boolean = false;
 
-  constructor() {
+  /**
+   * @param fd An existing SOCK_DGRAM unix socket to adopt, e.g. the one returned by {@link detach} in another thread.
+   */
+  constructor(fd?: number) {
     super();
     this.emit = this.emit.bind(this);
-    this.wrap = new DgramSocketWrap(this);
+    this.wrap = new DgramSocketWrap(this, fd);
     // TODO currently we can't get this object in rust side
     this.wrap.init(this.wrap);
-    this.wrap.startRecv();
+    // an unbound socket can't receive messages
+    if (fd !== undefined) {
+      this.startRecv();
+    }
     this.on('_data', this.onData);
     this.on('_error', this.onError);
   }
 
-  private onData = (buf: Buffer, filepath: string) => {
+  private onData = (buf: Buffer, filepath: string, timestamp?: RecvTimestamp | null) => {
     process.nextTick(() => {
-      this.emit('data', buf, filepath);
+      if (timestamp === undefined) {
+        this.emit('data', buf, filepath);
+      } else {
+        this.emit('data', buf, filepath, timestamp);
+      }
     });
   };
 
@@ -52,6 +77,13 @@ export class DgramSocket extends EventEmitter {
     this.emit('error', err);
   };
 
+  private startRecv() {
+    this.reading = true;
+    if (!this.paused) {
+      this.wrap.startRecv();
+    }
+  }
+
   private checkClosed() {
     if (this.closed) {
       throw new Error('DgramSocket has been closed');
@@ -60,15 +92,29 @@ export class DgramSocket extends EventEmitter {
 
   /**
    * Listen for datagram messages on a path.
+   *
+   * With `options.atomic`, the socket binds to a temporary sibling path and then renames it over `socketPath` so that an existing socket file will be replaced atomically.
+   *
+   * With `options.lock`, the socket takes an `flock()` on `${socketPath}.lock` and holds it until it closes. `bind()` throws if another live process holds the lock. A stale socket file at `socketPath` is removed only while the lock is held.
+   *
+   * With `options.unlinkOnTeardown`, the socket file is removed if the socket is still open when its environment, e.g. a worker, is torn down, unless it has been replaced by another socket.
    * @param socketPath
+   * @param options
    */
-  bind(socketPath: string) {
+  bind(socketPath: string, options?: BindOptions) {
     this.checkClosed();
-    this.wrap.bind(socketPath);
+    this.wrap.bind(socketPath, options);
+    this.startRecv();
   }
 
   /**
    * Send messages to the destination path.
+   *
+   * `onWrite` is called exactly once: with `null` when the message is sent, with the error when sending fails, or with an error whose `code` is `'ECANCELED'` when the socket closes before the message is sent. Every cancelled callback is called even if one of them throws, and {@link close} throws the first exception after `'close'` is emitted.
+   *
+   * It's never called if the socket gets garbage-collected without being closed, or if its environment is torn down, e.g. when a worker exits, because js can't be called there.
+   *
+   * If the message is sent right away, `onWrite` is called before `sendTo()` returns and an exception it throws is thrown by `sendTo()`. Otherwise the exception goes to `'uncaughtException'`.
    * @param buf
    * @param offset
    * @param length
@@ -86,6 +132,61 @@ export class DgramSocket extends EventEmitter {
     this.wrap.sendTo(buf, offset, length, destPath, onWrite);
   }
 
+  /**
+   * Send `bufs` together as one datagram to the destination path with `sendmsg()`, without concatenating them first, e.g. a header and a payload. `onWrite` is called like in {@link sendTo}.
+   * @param bufs
+   * @param destPath
+   * @param onWrite
+   */
+  sendvTo(bufs: Buffer[], destPath: string, onWrite?: SendCb) {
+    this.checkClosed();
+    this.wrap.sendvTo(bufs, destPath, onWrite);
+  }
+
+  /**
+   * Send a message synchronously, blocking in `poll()` instead of the event loop, e.g. in `process.on('exit')`. Messages that have been sent by {@link sendTo} are flushed first.
+   *
+   * It throws if sending fails or doesn't finish in `timeout` milliseconds, in which case the message is dropped. It waits forever if `timeout` is omitted.
+   * @param buf
+   * @param offset
+   * @param length
+   * @param destPath
+   * @param timeout
+   */
+  sendSync(
+    buf: Buffer,
+    offset: number,
+    length: number,
+    destPath: string,
+    timeout?: number
+  ) {
+    this.checkClosed();
+    this.wrap.sendSync(buf, offset, length, destPath, timeout);
+  }
+
+  /**
+   * Flush messages sent by {@link sendTo} synchronously, blocking in `poll()` instead of the event loop. Their callbacks are called before it returns, and it throws the first exception they throw.
+   *
+   * It throws if it doesn't finish in `timeout` milliseconds. Messages that haven't been sent stay in the queue.
+   * @param timeout
+   */
+  flushSync(timeout?: number) {
+    this.checkClosed();
+    this.wrap.flushSync(timeout);
+  }
+
+  /**
+   * Read a datagram synchronously, blocking in `poll()` instead of the event loop. No `'data'` event is emitted for the datagram.
+   *
+   * It throws if no datagram arrives in `timeout` milliseconds. It waits forever if `timeout` is omitted.
+   * @param timeout
+   * @returns
+   */
+  recvSync(timeout?: number): DgramMessage {
+    this.checkClosed();
+    return this.wrap.recvSync(timeout) as DgramMessage;
+  }
+
   /**
    * @returns the SO_RCVBUF socket receive buffer size in bytes.
    */
@@ -118,6 +219,107 @@ export class DgramSocket extends EventEmitter {
     return this.wrap.setSendBufferSize(size);
   }
 
+  /**
+   * Enable `SO_TIMESTAMPNS` so that `'data'` events come with the time when the kernel received each message as `[seconds, nanoseconds]` since the Unix epoch. Unlike timestamps taken in JavaScript, it isn't skewed by the delay of the event loop. Only supported on Linux.
+   * @param enabled
+   */
+  setRecvTimestamp(enabled: boolean) {
+    this.checkClosed();
+    this.wrap.setRecvTimestamp(enabled);
+  }
+
+  /**
+   * Return whether receive timestamps are enabled. See {@link setRecvTimestamp}.
+   * @returns
+   */
+  getRecvTimestamp(): boolean {
+    return this.wrap.getRecvTimestamp();
+  }
+
+  /**
+   * Return a snapshot of the counters of the socket, including messages and bytes sent and received, retries of sending, dropped and truncated messages, the queue of messages waiting to be sent and the latency from `sendTo()` to sending.
+   * @returns
+   */
+  stats(): SocketStats {
+    return this.wrap.stats();
+  }
+
+  /**
+   * Return the bytes waiting to be read in the kernel, i.e. `SIOCINQ`/`FIONREAD`. On Linux, it's the size of the next datagram.
+   * @returns
+   */
+  getUnreadBytes(): number {
+    return this.wrap.getUnreadBytes();
+  }
+
+  /**
+   * Return the bytes that have been sent but not consumed by the other side yet, i.e. `SIOCOUTQ`. Linux counts the memory of messages including their overhead.
+   * @returns
+   */
+  getUnsentBytes(): number {
+    return this.wrap.getUnsentBytes();
+  }
+
+  /**
+   * Return the size of the next datagram waiting to be read, or `null` if there is none.
+   * @returns
+   */
+  getNextDatagramSize(): number | null {
+    return this.wrap.getNextDatagramSize();
+  }
+
+  /**
+   * Return a copy of the next datagram waiting to be read without removing it from the queue, i.e. `MSG_PEEK`, or `null` if there is none.
+   *
+   * Only the first `size` bytes are copied if `size` is given. With `setSockOpt('SO_PEEK_OFF', 0)` on Linux, every peek starts where the previous one stopped so that queued datagrams can be stepped through. Use {@link pause} to keep datagrams from being read while peeking at them.
+   * @param size
+   * @returns
+   */
+  peek(size?: number): Buffer | null {
+    this.checkClosed();
+    return this.wrap.peek(size);
+  }
+
+  /**
+   * Stop reading datagrams so that they stay in the kernel until {@link resume} is called. Datagrams that have been read are still emitted.
+   */
+  pause() {
+    this.checkClosed();
+    this.paused = true;
+    this.wrap.stopRecv();
+  }
+
+  /**
+   * Resume reading datagrams after {@link pause}.
+   */
+  resume() {
+    this.checkClosed();
+    this.paused = false;
+    if (this.reading) {
+      this.wrap.startRecv();
+    }
+  }
+
+  /** {@inheritDoc SockOpts.getSockOpt} */
+  getSockOpt(name: string): SockOptValue {
+    return this.wrap.getSockOpt(name) as SockOptValue;
+  }
+
+  /** {@inheritDoc SockOpts.setSockOpt} */
+  setSockOpt(name: string, value: SockOptValue) {
+    this.wrap.setSockOpt(name, value);
+  }
+
+  /** {@inheritDoc SockOpts.getRawSockOpt} */
+  getRawSockOpt(level: number, optname: number, length: number): Buffer {
+    return this.wrap.getRawSockOpt(level, optname, length);
+  }
+
+  /** {@inheritDoc SockOpts.setRawSockOpt} */
+  setRawSockOpt(level: number, optname: number, value: Buffer) {
+    this.wrap.setRawSockOpt(level, optname, value);
+  }
+
   /**
    * Returns the bound address.
    * @returns
@@ -126,6 +328,43 @@ export class DgramSocket extends EventEmitter {
     return this.wrap.address();
   }
 
+  /**
+   * Stop listening for data and return the fd of the socket without closing it. The fd can be passed to another thread, e.g. a worker, and adopted by `new DgramSocket(fd)` there.
+   *
+   * It throws if there are messages waiting to be sent, because they aren't handed over with the fd. Call {@link flushSync} first to send them. It also throws if the socket holds a path lock taken by `bind()` with `lock: true`. The socket can't be used anymore and no `'close'` event will be emitted.
+   *
+   * `unlinkOnTeardown` of `bind()` isn't handed over: the path is never unlinked after the socket is detached, neither here nor by the socket adopting the fd.
+   * @returns the fd
+   */
+  detach(): number {
+    this.checkClosed();
+    const fd = this.wrap.detach();
+    this.closed = true;
+    return fd;
+  }
+
+  /**
+   * Close the socket once messages waiting to be sent are sent and their callbacks are called, instead of dropping them like {@link close}. `sendTo()` throws after it.
+   *
+   * If `timeout` is given, the socket is closed by {@link close} after `timeout` milliseconds anyway and the callbacks of messages that haven't been sent get an error whose `code` is `'ECANCELED'`.
+   * @param timeout
+   */
+  closeWhenFlushed(timeout?: number) {
+    if (this.closed || this.closing) {
+      return;
+    }
+    this.closing = true;
+    let timer: NodeJS.Timeout | undefined;
+    if (timeout !== undefined) {
+      timer = setTimeout(() => this.close(), timeout);
+    }
+    this.once('close', () => {
+      this.closed = true;
+      clearTimeout(timer);
+    });
+    this.wrap.closeWhenFlushed();
+  }
+
   /**
    * Close the underlying socket and stop listening for data on it.
    * @returns
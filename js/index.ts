@@ -1,11 +1,36 @@
 import * as workerThreads from 'worker_threads'
 import { initCleanupHook } from './addon'
 
-export { SendCb, DgramSocket } from './dgram'
-export { NotifyCb, SeqpacketSocket, SeqpacketServer } from './seqpacket'
-export { createReuseportFd, closeFd } from './socket'
+export { SendCb, DgramMessage, DgramSocket } from './dgram'
+export { NotifyCb, WriteCb, SeqpacketSocketOptions, SeqpacketSocket, SeqpacketServer } from './seqpacket'
+export {
+  ListenerCb,
+  SockOptValue,
+  SockOpts,
+  RecvTimestamp,
+  createReuseportFd,
+  createReuseportUdpFd,
+  createReuseportListener,
+  closeFd,
+  setUnclosedSocketWarning,
+  getSockOpt,
+  setSockOpt,
+  getRawSockOpt,
+  setRawSockOpt,
+} from './socket'
+export {
+  AddressInfo,
+  BindOptions,
+  ReuseportListener,
+  ReuseportOptions,
+  BpfInstruction,
+  Linger,
+  SocketStats,
+} from './addon'
 
 // Node.js will abort when threads are termiated if we don't clean up uv handles.
+// Each environment closes its own handles in its clean up hook, which is also
+// registered once a socket gets created.
 if (!workerThreads.isMainThread) {
   initCleanupHook()
 }
@@ -1,9 +1,20 @@
 import { EventEmitter } from 'events';
 import {
-  SeqpacketSocketWrap
+  SeqpacketSocketWrap,
+  BindOptions,
+  SocketStats,
 } from './addon';
+import { SockOpts, SockOptValue, RecvTimestamp } from './socket';
 
 export type NotifyCb = () => void;
+export type WriteCb = (err: null | Error) => void;
+
+export interface SeqpacketSocketOptions {
+  /**
+   * If `false`, the socket ends its write side automatically once it receives `'end'` and messages written before are flushed, and then closes. Default `true`.
+   */
+  allowHalfOpen?: boolean;
+}
 
 /**
  * SeqpacketServer is used to create a SOCK_SEQPACKET server.
@@ -26,13 +37,19 @@ export type NotifyCb = () => void;
  *
  * Emitted when the server closes.
  */
-export class SeqpacketServer extends EventEmitter {
+export class SeqpacketServer extends EventEmitter implements SockOpts {
   private closed: boolean = false;
   private wrap: SeqpacketSocketWrap;
+  private options?: SeqpacketSocketOptions;
 
-  constructor() {
+  /**
+   * @param options Options of accepted sockets.
+   */
+  constructor(options?: SeqpacketSocketOptions) {
     super();
 
+    this.options = options;
+
     this.emit = this.emit.bind(this);
     this.wrap = new SeqpacketSocketWrap(this);
     // TODO currently we can't get this object in rust side
@@ -54,7 +71,7 @@ export class SeqpacketServer extends EventEmitter {
   };
 
   private onConnection = (fd: number, addr: string) => {
-    const socket = new SeqpacketSocket(fd);
+    const socket = new SeqpacketSocket(fd, this.options);
     this.emit('connection', socket, addr);
   };
 
@@ -83,12 +100,43 @@ export class SeqpacketServer extends EventEmitter {
 
   /**
    * Start a server listening for connections on the given path. This function is synchronous.
+   *
+   * With `options.atomic`, the server binds to a temporary sibling path, calls `listen()` and then renames it over `bindpath`. An existing socket file at `bindpath`, e.g. the one of the old process in a zero-downtime restart, will be replaced atomically so that clients never see a missing or half-ready socket.
+   *
+   * With `options.lock`, the server takes an `flock()` on `${bindpath}.lock` and holds it until it closes. `listen()` throws if another live process holds the lock. A stale socket file at `bindpath` is removed only while the lock is held.
+   *
+   * With `options.unlinkOnTeardown`, the socket file is removed if the server is still open when its environment, e.g. a worker, is torn down, unless it has been replaced by another socket.
    * @param bindpath
    * @param backlog
+   * @param options
    */
-  listen(bindpath: string, backlog: number = 511) {
+  listen(bindpath: string, backlog: number = 511, options?: BindOptions) {
+    this.checkClosed();
+    this.wrap.listen(bindpath, backlog, options);
+  }
+
+  /** {@inheritDoc SockOpts.getSockOpt} */
+  getSockOpt(name: string): SockOptValue {
+    this.checkClosed();
+    return this.wrap.getSockOpt(name) as SockOptValue;
+  }
+
+  /** {@inheritDoc SockOpts.setSockOpt} */
+  setSockOpt(name: string, value: SockOptValue) {
+    this.checkClosed();
+    this.wrap.setSockOpt(name, value);
+  }
+
+  /** {@inheritDoc SockOpts.getRawSockOpt} */
+  getRawSockOpt(level: number, optname: number, length: number): Buffer {
+    this.checkClosed();
+    return this.wrap.getRawSockOpt(level, optname, length);
+  }
+
+  /** {@inheritDoc SockOpts.setRawSockOpt} */
+  setRawSockOpt(level: number, optname: number, value: Buffer) {
     this.checkClosed();
-    this.wrap.listen(bindpath, backlog);
+    this.wrap.setRawSockOpt(level, optname, value);
   }
 
   /**
@@ -118,7 +166,10 @@ export class SeqpacketServer extends EventEmitter {
  * ### Event: `'data'`
  *
  * - buffer `Buffer`
- * Emitted when data is received. All message boundaries in incoming datagrams are preserved.
+ * - timestamp `RecvTimestamp | null`
+ * Emitted when data is received. All message boundaries in incoming datagrams are preserved and zero-length messages are emitted as empty buffers. `timestamp` is only passed if it's enabled by {@link setRecvTimestamp}.
+ *
+ * On Linux, sockets enable `SO_TIMESTAMPNS` when they are created, even if {@link setRecvTimestamp} isn't called. Every message comes with a timestamp while the end of stream doesn't, which is how zero-length messages are told apart from `'end'`. On MacOS, a zero-length message is taken as `'end'`.
  *
  * ### Event: `'end'`
  * Emitted when the other end of the socket signals the end of transmission, thus ending the readable side of the socket.
@@ -128,26 +179,37 @@ export class SeqpacketServer extends EventEmitter {
  * Emitted when an error occurs. The 'close' event will be called directly following this event.
  *
  * ### Event: `'close'`
- * Emitted once the socket is fully closed.
+ * Emitted once the socket is fully closed, even if callbacks of {@link write} cancelled by it throw.
  */
-export class SeqpacketSocket extends EventEmitter {
+export class SeqpacketSocket extends EventEmitter implements SockOpts {
   private wrap: SeqpacketSocketWrap;
   private destroyed: boolean = false;
+  private closing: boolean = false;
   private connectCb?: NotifyCb;
   private shutdownCb?: NotifyCb;
   private shutdown: boolean = false;
+  private readShutdown: boolean = false;
   private isEnd: boolean = false
+  private reading: boolean = false;
+  private paused: boolean = false;
 
-  constructor(fd?: number) {
+  /**
+   * @param fd An existing SOCK_SEQPACKET unix socket to adopt.
+   * @param options
+   */
+  constructor(fd?: number, options?: SeqpacketSocketOptions) {
     super();
 
     this.emit = this.emit.bind(this);
     this.wrap = new SeqpacketSocketWrap(this, fd);
     // TODO currently we can't get this object in rust side
     this.wrap.init(this.wrap);
+    if (options && options.allowHalfOpen === false) {
+      this.wrap.setAllowHalfOpen(false);
+    }
 
     if (fd) {
-      this.wrap.startRecv();
+      this.startRecv();
     }
     this.on('_data', this.onData);
     this.on('end', this.onEnd);
@@ -170,8 +232,12 @@ export class SeqpacketSocket extends EventEmitter {
     }
   };
 
-  private onData = (buf: Buffer) => {
-    this.emit('data', buf);
+  private onData = (buf: Buffer, timestamp?: RecvTimestamp | null) => {
+    if (timestamp === undefined) {
+      this.emit('data', buf);
+    } else {
+      this.emit('data', buf, timestamp);
+    }
   };
 
   private checkDestroyed() {
@@ -188,7 +254,7 @@ export class SeqpacketSocket extends EventEmitter {
   };
 
   private onConnect = () => {
-    this.wrap.startRecv();
+    this.startRecv();
     this.emit('connect');
     if (this.connectCb) {
       this.connectCb();
@@ -196,16 +262,27 @@ export class SeqpacketSocket extends EventEmitter {
     }
   };
 
+  private startRecv() {
+    this.reading = true;
+    if (!this.paused) {
+      this.wrap.startRecv();
+    }
+  }
+
   private checkClose() {
-    if (this.isEnd && this.shutdown) {
+    if ((this.isEnd || this.readShutdown) && this.shutdown) {
       this.destroy()
     }
   }
 
-  // address(): string {
-  //   this.checkDestroyed();
-  //   return seqAddress(this);
-  // }
+  /**
+   * Returns the bound address, or `''` if the socket isn't bound, e.g. a client socket.
+   * @returns
+   */
+  address(): string {
+    this.checkDestroyed();
+    return this.wrap.address();
+  }
 
   /**
    * Initiate a connection on a given socket.
@@ -222,31 +299,102 @@ export class SeqpacketSocket extends EventEmitter {
   }
 
   /**
-   * Sends data on the socket. The `cb` is called when data is written to operating system.
+   * Sends data on the socket. The `cb` is called exactly once: with `null` when data is written to operating system, with the error when writing fails, or with an error whose `code` is `'ECANCELED'` when the socket closes before that. Every cancelled callback is called even if one of them throws, and {@link destroy} throws the first exception after `'close'` is emitted.
+   *
+   * It's never called if the socket gets garbage-collected without being destroyed, or if its environment is torn down, e.g. when a worker exits, because js can't be called there.
+   *
+   * If the message is written right away, `cb` is called before `write()` returns and an exception it throws is thrown by `write()`. Otherwise the exception goes to `'uncaughtException'`.
+   *
+   * Every call sends exactly one message. It throws an `EMSGSIZE` error if the message is larger than {@link getMaxMsgSize}.
    * @param buf
    * @param offset
    * @param length
    * @param cb
    */
-  write(buf: Buffer, offset?: number, length?: number, cb?: NotifyCb) {
+  write(buf: Buffer, offset?: number, length?: number, cb?: WriteCb) {
     if (arguments.length === 1) {
       offset = 0
       length = buf.length
     }
     this.checkDestroyed();
     const v = offset || 0;
-    this.wrap.write(buf, offset || 0, length || buf.length, cb);
+    this.wrap.write(buf, offset || 0, length ?? buf.length, cb);
+  }
+
+  /**
+   * Sends `bufs` together as one message with `sendmsg()`, without concatenating them first, e.g. a header and a payload. The `cb` is called like in {@link write}.
+   * @param bufs
+   * @param cb
+   */
+  writev(bufs: Buffer[], cb?: WriteCb) {
+    this.checkDestroyed();
+    this.wrap.writev(bufs, cb);
+  }
+
+  /**
+   * Sends data on the socket synchronously, blocking in `poll()` instead of the event loop, e.g. in `process.on('exit')`. Messages that have been written by {@link write} are flushed first. Like {@link write}, it sends exactly one message.
+   *
+   * It throws if writing fails or doesn't finish in `timeout` milliseconds, in which case the message is dropped. It waits forever if `timeout` is omitted.
+   * @param buf
+   * @param offset
+   * @param length
+   * @param timeout
+   */
+  writeSync(buf: Buffer, offset: number = 0, length: number = buf.length, timeout?: number) {
+    this.checkDestroyed();
+    this.wrap.writeSync(buf, offset, length, timeout);
+  }
+
+  /**
+   * Flushes messages written by {@link write} synchronously, blocking in `poll()` instead of the event loop. Their callbacks are called before it returns, and it throws the first exception they throw.
+   *
+   * It throws if writing fails or doesn't finish in `timeout` milliseconds. Messages that haven't been written stay in the queue.
+   * @param timeout
+   */
+  flushSync(timeout?: number) {
+    this.checkDestroyed();
+    this.wrap.flushSync(timeout);
+  }
+
+  /**
+   * Reads a message synchronously, blocking in `poll()` instead of the event loop. No `'data'` event is emitted for the message.
+   *
+   * It returns `null` if the other side has ended and throws if no message arrives in `timeout` milliseconds. It waits forever if `timeout` is omitted.
+   * @param timeout
+   * @returns
+   */
+  recvSync(timeout?: number): Buffer | null {
+    this.checkDestroyed();
+    return this.wrap.recvSync(timeout);
   }
 
   /**
    * Half-closes the socket. i.e., it sends a FIN packet. It is possible the server will still send some data.
+   *
+   * The write side is shut down with `SHUT_WR` once messages waiting to be sent are flushed, or with `SHUT_RDWR` if the read side has been shut down by {@link shutdownRead}. The socket closes automatically once both sides are done.
    * @param cb
    */
   end(cb?: NotifyCb) {
+    if (this.shutdown) {
+      if (cb) {
+        process.nextTick(cb);
+      }
+      return;
+    }
     this.shutdownCb = cb;
     this.wrap.shutdownWhenFlushed();
   }
 
+  /**
+   * Shuts down the read side of the socket with `SHUT_RD`. No more `'data'` events will be emitted, messages that haven't been read are dropped and the other side fails to write. The socket can still write until {@link end} is called.
+   */
+  shutdownRead() {
+    this.checkDestroyed();
+    this.wrap.shutdownRead();
+    this.readShutdown = true;
+    this.checkClose();
+  }
+
   /**
    * Return the size of buffer that SeqpacketSocket uses to receive data. The data will be truncated if the buffer size is not large enough.
    *
@@ -266,6 +414,109 @@ export class SeqpacketSocket extends EventEmitter {
     this.wrap.setReadBufSize(size);
   }
 
+  /**
+   * Enable `SO_TIMESTAMPNS` so that `'data'` events come with the time when the kernel received each message as `[seconds, nanoseconds]` since the Unix epoch. Unlike timestamps taken in JavaScript, it isn't skewed by the delay of the event loop.
+   *
+   * Disabling it only stops passing timestamps to `'data'` events. `SO_TIMESTAMPNS` itself stays enabled, see the `'data'` event.
+   * @param enabled
+   */
+  setRecvTimestamp(enabled: boolean) {
+    this.checkDestroyed();
+    this.wrap.setRecvTimestamp(enabled);
+  }
+
+  /**
+   * Return whether receive timestamps are enabled. See {@link setRecvTimestamp}.
+   * @returns
+   */
+  getRecvTimestamp(): boolean {
+    return this.wrap.getRecvTimestamp();
+  }
+
+  /**
+   * Return a snapshot of the counters of the socket, including messages and bytes sent and received, retries of writing, dropped and truncated messages, the queue of messages waiting to be sent and the latency from `write()` to sending.
+   * @returns
+   */
+  stats(): SocketStats {
+    return this.wrap.stats();
+  }
+
+  /**
+   * Return the bytes waiting to be read in the kernel, i.e. `SIOCINQ`/`FIONREAD`.
+   * @returns
+   */
+  getUnreadBytes(): number {
+    return this.wrap.getUnreadBytes();
+  }
+
+  /**
+   * Return the bytes that have been sent but not consumed by the other side yet, i.e. `SIOCOUTQ`. Linux counts the memory of messages including their overhead.
+   * @returns
+   */
+  getUnsentBytes(): number {
+    return this.wrap.getUnsentBytes();
+  }
+
+  /**
+   * Return the size of the largest message that the socket can send, which is limited by the send buffer, i.e. `SO_SNDBUF`. Larger messages can't be written.
+   * @returns
+   */
+  getMaxMsgSize(): number {
+    return this.wrap.getMaxMsgSize();
+  }
+
+  /**
+   * Return a copy of the next message waiting to be read without removing it from the queue, i.e. `MSG_PEEK`, or `null` if there is none. An empty buffer is either an empty message or the end of the other side.
+   *
+   * Only the first `size` bytes are copied if `size` is given. With `setSockOpt('SO_PEEK_OFF', 0)`, every peek starts where the previous one stopped so that queued messages can be stepped through. Use {@link pause} to keep messages from being read while peeking at them.
+   * @param size
+   * @returns
+   */
+  peek(size?: number): Buffer | null {
+    this.checkDestroyed();
+    return this.wrap.peek(size);
+  }
+
+  /**
+   * Stop reading messages so that they stay in the kernel until {@link resume} is called. No more 'data' events are emitted once it's called.
+   */
+  pause() {
+    this.checkDestroyed();
+    this.paused = true;
+    this.wrap.stopRecv();
+  }
+
+  /**
+   * Resume reading messages after {@link pause}.
+   */
+  resume() {
+    this.checkDestroyed();
+    this.paused = false;
+    if (this.reading) {
+      this.wrap.startRecv();
+    }
+  }
+
+  /** {@inheritDoc SockOpts.getSockOpt} */
+  getSockOpt(name: string): SockOptValue {
+    return this.wrap.getSockOpt(name) as SockOptValue;
+  }
+
+  /** {@inheritDoc SockOpts.setSockOpt} */
+  setSockOpt(name: string, value: SockOptValue) {
+    this.wrap.setSockOpt(name, value);
+  }
+
+  /** {@inheritDoc SockOpts.getRawSockOpt} */
+  getRawSockOpt(level: number, optname: number, length: number): Buffer {
+    return this.wrap.getRawSockOpt(level, optname, length);
+  }
+
+  /** {@inheritDoc SockOpts.setRawSockOpt} */
+  setRawSockOpt(level: number, optname: number, value: Buffer) {
+    this.wrap.setRawSockOpt(level, optname, value);
+  }
+
   /**
    * Reference the socket so that it will prevent Node.js process from exiting automatically.
    */
@@ -280,6 +531,43 @@ export class SeqpacketSocket extends EventEmitter {
     this.wrap.uvUnrefer();
   }
 
+  /**
+   * Stop receiving data and return the fd of the socket without closing it. The fd can be passed to another thread, e.g. a worker, and adopted by `new SeqpacketSocket(fd)` there.
+   *
+   * It throws if there are messages waiting to be sent, because they aren't handed over with the fd. Call {@link flushSync} first to send them. The socket can't be used anymore and no `'close'` event will be emitted.
+   * @returns the fd
+   */
+  detach(): number {
+    this.checkDestroyed();
+    const fd = this.wrap.detach();
+    this.destroyed = true;
+    return fd;
+  }
+
+  /**
+   * Closes the socket once messages waiting to be sent are written and their callbacks are called, instead of dropping them like {@link destroy}. The write side is shut down like {@link end} before that, but it doesn't wait for the other side to end.
+   *
+   * If `timeout` is given, the socket is destroyed after `timeout` milliseconds anyway and the callbacks of messages that haven't been written get an error whose `code` is `'ECANCELED'`.
+   * @param timeout
+   */
+  closeWhenFlushed(timeout?: number) {
+    if (this.destroyed || this.closing) {
+      return;
+    }
+    this.closing = true;
+    let timer: NodeJS.Timeout | undefined;
+    if (timeout !== undefined) {
+      timer = setTimeout(() => this.destroy(), timeout);
+    }
+    this.once('close', () => clearTimeout(timer));
+    if (this.shutdown) {
+      this.destroy();
+      return;
+    }
+    this.once('_shutdown', () => this.destroy());
+    this.wrap.shutdownWhenFlushed();
+  }
+
   /**
    * Ensures that no more I/O activity happens on this socket. Destroys the stream and closes the connection.
    */
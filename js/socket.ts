@@ -1,5 +1,63 @@
 import { isIPv4, isIP } from 'net';
-import { socketNewSoReuseportFd, socketClose } from './addon';
+import {
+  socketNewSoReuseportFd,
+  socketNewSoReuseportUdpFd,
+  socketClose,
+  socketSetUnclosedWarning,
+  socketLookup,
+  socketGetSockOpt,
+  socketSetSockOpt,
+  socketGetRawSockOpt,
+  socketSetRawSockOpt,
+  Linger,
+  ReuseportListener,
+  ReuseportOptions,
+} from './addon';
+
+export type SockOptValue = number | boolean | Linger;
+
+/**
+ * Socket options of {@link DgramSocket}, {@link SeqpacketSocket} and {@link SeqpacketServer}. {@link getSockOpt} and the other functions of the same names do the same for fds.
+ */
+export interface SockOpts {
+  /**
+   * Get a SOL_SOCKET level socket option by its name, e.g. `'SO_RCVBUF'`. Timeouts like `'SO_RCVTIMEO'` are in milliseconds and `'SO_LINGER'` is `{ enabled, seconds }`.
+   * @param name
+   * @returns
+   */
+  getSockOpt(name: string): SockOptValue;
+
+  /**
+   * Set a SOL_SOCKET level socket option by its name. See {@link getSockOpt}.
+   * @param name
+   * @param value
+   */
+  setSockOpt(name: string, value: SockOptValue): void;
+
+  /**
+   * Get a socket option by `level` and `optname`. At most `length` bytes are returned.
+   * @param level
+   * @param optname
+   * @param length
+   * @returns
+   */
+  getRawSockOpt(level: number, optname: number, length: number): Buffer;
+
+  /**
+   * Set a socket option by `level` and `optname` with the bytes of `value`.
+   * @param level
+   * @param optname
+   * @param value
+   */
+  setRawSockOpt(level: number, optname: number, value: Buffer): void;
+}
+
+/**
+ * `[seconds, nanoseconds]` since the Unix epoch when the kernel received a message.
+ */
+export type RecvTimestamp = [number, number];
+
+export type ListenerCb = (err: Error | null, listener?: ReuseportListener) => void;
 
 /**
  * Create a TCP socket with SO_REUSEADDR and SO_REUSEPORT enabled.
@@ -12,13 +70,66 @@ import { socketNewSoReuseportFd, socketClose } from './addon';
  * server.listen({ fd }, () => { console.log('listen() successfully') })
  * ```
  *
+ * On Linux, `options` can attach a classic BPF program (SO_ATTACH_REUSEPORT_CBPF) to control how connections are distributed among the sockets of the group:
+ *
+ * ```typescript
+ * // pin connections to the worker on the CPU that receives them
+ * const fd = createReuseportFd(9229, '0.0.0.0', { steering: 'cpu', groupSize: os.cpus().length });
+ * ```
+ *
+ * A program replaces the one of the whole group. The TCP socket will be in the listening state when a program is attached.
+ *
+ * Socket options like `ipv6Only`, `freebind`, `bindToDevice`, `deferAccept` and `fastOpen` are set before the socket binds or listens. Set `listen` to call listen() natively with `backlog`:
+ *
+ * ```typescript
+ * const fd = createReuseportFd(9229, 'fe80::1%eth0', { ipv6Only: true, fastOpen: 64, listen: true, backlog: 1024 });
+ * ```
+ *
  * @param port
  * @param host
+ * @param options
  * @returns Return a fd binds to the address.
  */
 export function createReuseportFd(
   port: number = 0,
-  host: string = '0.0.0.0'
+  host: string = '0.0.0.0',
+  options?: ReuseportOptions
+): number {
+  if (!isIP(host)) {
+    throw new Error('invalid host');
+  }
+
+  const domain = isIPv4(host) ? 'ipv4' : 'ipv6';
+
+  const fd = socketNewSoReuseportFd(domain, port, host, options);
+
+  return fd;
+}
+
+/**
+ * Create a UDP socket with SO_REUSEADDR and SO_REUSEPORT enabled and bind it to the address.
+ *
+ * Use the returned fd to create a [dgram.Socket](https://nodejs.org/docs/latest-v16.x/api/dgram.html#class-dgramsocket) so that multiple processes can receive datagrams of the same port:
+ *
+ * ```typescript
+ * const fd = createReuseportUdpFd(514, '0.0.0.0');
+ * const socket = require('dgram').createSocket('udp4');
+ * socket.bind({ fd }, () => { console.log('bind() successfully') })
+ * ```
+ *
+ * Note that the type passed to `dgram.createSocket()` should be `'udp6'` if `host` is an IPv6 address.
+ *
+ * See {@link createReuseportFd} for `options`.
+ *
+ * @param port
+ * @param host
+ * @param options
+ * @returns Return a fd binds to the address.
+ */
+export function createReuseportUdpFd(
+  port: number = 0,
+  host: string = '0.0.0.0',
+  options?: ReuseportOptions
 ): number {
   if (!isIP(host)) {
     throw new Error('invalid host');
@@ -26,11 +137,81 @@ export function createReuseportFd(
 
   const domain = isIPv4(host) ? 'ipv4' : 'ipv6';
 
-  const fd = socketNewSoReuseportFd(domain, port, host);
+  const fd = socketNewSoReuseportUdpFd(domain, port, host, options);
 
   return fd;
 }
 
+/**
+ * Create a {@link ReuseportListener}, i.e. a TCP socket with SO_REUSEADDR and SO_REUSEPORT enabled. `host` can be a hostname that is resolved with the getaddrinfo() of libuv and the first address is used.
+ *
+ * Unlike {@link createReuseportFd}, the listener tells the port it binds to and closes the fd when it's closed or gets garbage collected, unless the fd has been handed off by `handOff()`. Reading `fd` doesn't hand it off:
+ *
+ * ```typescript
+ * createReuseportListener(0, 'localhost', (err, listener) => {
+ *   console.log(listener.address().port)
+ *   // the server owns the fd from now on
+ *   require('net').createServer().listen({ fd: listener.handOff() })
+ * })
+ * ```
+ *
+ * See {@link createReuseportFd} for `options`.
+ *
+ * @param port
+ * @param host
+ * @param options
+ * @param cb
+ */
+export function createReuseportListener(port: number, host: string, cb: ListenerCb): void;
+export function createReuseportListener(
+  port: number,
+  host: string,
+  options: ReuseportOptions | undefined,
+  cb: ListenerCb
+): void;
+export function createReuseportListener(
+  port: number,
+  host: string,
+  options: ReuseportOptions | ListenerCb | undefined,
+  cb?: ListenerCb
+): void {
+  if (typeof options === 'function') {
+    cb = options;
+    options = undefined;
+  }
+  const callback = cb as ListenerCb;
+  const listenerOptions = options;
+
+  const create = (address: string) => {
+    const domain = isIPv4(address) ? 'ipv4' : 'ipv6';
+    let listener: ReuseportListener;
+    try {
+      listener = new ReuseportListener(domain, port, address, listenerOptions);
+    } catch (err) {
+      callback(err as Error);
+      return;
+    }
+    callback(null, listener);
+  };
+
+  if (isIP(host)) {
+    process.nextTick(create, host);
+    return;
+  }
+
+  socketLookup(host, (err: Error | null, addresses?: { address: string }[]) => {
+    if (err) {
+      callback(err);
+      return;
+    }
+    if (!addresses || addresses.length === 0) {
+      callback(new Error(`no address found for '${host}'`));
+      return;
+    }
+    create(addresses[0].address);
+  });
+}
+
 /**
  * Close a fd.
  *
@@ -40,3 +221,58 @@ export function createReuseportFd(
 export function closeFd(fd: number) {
   socketClose(fd);
 }
+
+/**
+ * Print a warning to stderr when a socket is garbage-collected without being closed. Its fd is closed anyway.
+ *
+ * It only applies to sockets of the current environment, i.e. calling it in the main thread doesn't enable it in workers.
+ *
+ * Sockets that are listening, connecting, reading or writing are kept alive until they get closed. A `DgramSocket` only reads once it's bound or adopts an fd.
+ * @param enabled
+ */
+export function setUnclosedSocketWarning(enabled: boolean) {
+  socketSetUnclosedWarning(enabled);
+}
+
+/**
+ * {@link SockOpts.getSockOpt} of a fd.
+ * @param fd
+ * @param name
+ * @returns
+ */
+export function getSockOpt(fd: number, name: string): SockOptValue {
+  return socketGetSockOpt(fd, name) as SockOptValue;
+}
+
+/**
+ * {@link SockOpts.setSockOpt} of a fd.
+ * @param fd
+ * @param name
+ * @param value
+ */
+export function setSockOpt(fd: number, name: string, value: SockOptValue) {
+  socketSetSockOpt(fd, name, value);
+}
+
+/**
+ * {@link SockOpts.getRawSockOpt} of a fd.
+ * @param fd
+ * @param level
+ * @param optname
+ * @param length
+ * @returns
+ */
+export function getRawSockOpt(fd: number, level: number, optname: number, length: number): Buffer {
+  return socketGetRawSockOpt(fd, level, optname, length);
+}
+
+/**
+ * {@link SockOpts.setRawSockOpt} of a fd.
+ * @param fd
+ * @param level
+ * @param optname
+ * @param value
+ */
+export function setRawSockOpt(fd: number, level: number, optname: number, value: Buffer) {
+  socketSetRawSockOpt(fd, level, optname, value);
+}
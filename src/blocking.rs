@@ -0,0 +1,56 @@
+use std::time::{Duration, Instant};
+
+use libc::{pollfd, EINTR};
+use napi::Result;
+use nix::errno::errno;
+
+use crate::util::{error, resolve_libc_err};
+
+/**
+ * The time when a blocking operation with a timeout in milliseconds gives
+ * up. `None` means it waits forever.
+ */
+pub(crate) fn deadline(timeout: Option<u32>) -> Option<Instant> {
+  timeout.map(|ms| Instant::now() + Duration::from_millis(ms as u64))
+}
+
+/**
+ * Wait with poll() until `fd` gets any of `events`, e.g. POLLIN, bypassing
+ * the event loop. Returns false if the deadline has passed.
+ */
+pub(crate) fn wait_fd(fd: i32, events: i16, deadline: Option<Instant>) -> Result<bool> {
+  loop {
+    let timeout = match deadline {
+      None => -1,
+      Some(deadline) => {
+        let now = Instant::now();
+        if now >= deadline {
+          return Ok(false);
+        }
+        // less than 1ms left shouldn't make poll() return immediately
+        let left = (deadline - now).as_millis().max(1);
+        left.min(i32::MAX as u128) as i32
+      }
+    };
+
+    let mut fds = pollfd {
+      fd,
+      events,
+      revents: 0,
+    };
+    let ret = unsafe { libc::poll(&mut fds as *mut _, 1, timeout) };
+    if ret == -1 && errno() == EINTR {
+      continue;
+    }
+    resolve_libc_err(ret)?;
+
+    // POLLERR and POLLHUP are reported to the caller by the next syscall
+    if ret > 0 {
+      return Ok(true);
+    }
+  }
+}
+
+pub(crate) fn timed_out(op: &str) -> napi::Error {
+  error(format!("{} timed out", op))
+}
@@ -1,19 +1,29 @@
+use std::cell::{Cell, RefCell};
 use std::collections::LinkedList;
 use std::mem;
+use std::ptr;
+use std::time::Instant;
 
-use libc::{
-  self, c_void, iovec, msghdr, sockaddr, sockaddr_un, EAGAIN, EINTR, ENOBUFS, EWOULDBLOCK,
-};
+use libc::{self, c_void, msghdr, sockaddr_un, timespec, EAGAIN, EINTR, ENOBUFS, EWOULDBLOCK};
 use napi::{Env, JsBuffer, JsFunction, JsNumber, JsObject, JsString, JsUnknown, Ref, Result};
 use nix::{self, errno::errno};
 use uv_sys::sys::{self, uv_poll_event};
 
-use crate::socket::{close, get_loop, sockaddr_from_string, Emitter, HandleData};
+use crate::blocking;
+use crate::queue;
+use crate::socket::{
+  self, bind_with_options, close, get_loop, sockaddr_from_string, BindOptions, BoundPath, Emitter,
+  FirstError, HandleData, PathLock,
+};
+use crate::sockopt;
+use crate::stats::{Counters, SocketStats};
+use crate::timestamp::{self, CmsgBuf};
 use crate::util::{
   addr_to_string, buf_into_vec, check_emit, error, get_err, i8_slice_into_u8_slice,
-  resolve_libc_err, resolve_uv_err, set_clo_exec, set_non_block, socket_addr_to_string,
+  resolve_libc_err, resolve_uv_err, set_clo_exec, set_non_block, socket_addr_to_string, uv_err_msg,
+  MsgBufs,
 };
-use crate::uv_handle::{insert_handle, remove_handle};
+use crate::uv_handle::{insert_handle, remove_handle, set_owner, Teardown};
 
 #[allow(dead_code)]
 fn string_from_i8_slice(slice: &[i8]) -> Result<String> {
@@ -25,18 +35,78 @@ fn string_from_i8_slice(slice: &[i8]) -> Result<String> {
 }
 
 struct MsgInfoItem {
-  msg: Vec<u8>,
+  msg: MsgBufs,
   sockaddr: sockaddr_un,
   cb: Option<Ref<()>>,
+  enqueued_at: Instant,
+}
+
+/**
+ * A datagram read by recv_msg().
+ */
+struct RecvMsg {
+  buf: Vec<u8>,
+  path: String,
+  timestamp: Option<timespec>,
 }
 
+/**
+ * js callbacks called by the wrap, e.g. listeners of "data", might call its
+ * methods again, so that methods only take `&self` and the state is kept in
+ * cells like `SeqpacketSocketWrap`.
+ */
 #[napi]
 pub struct DgramSocketWrap {
   fd: i32,
   env: Env,
-  handle: *mut sys::uv_poll_t,
-  msg_queue: LinkedList<MsgInfoItem>,
+  handle: Cell<*mut sys::uv_poll_t>,
+  msg_queue: RefCell<LinkedList<MsgInfoItem>>,
   emitter: Emitter,
+  /**
+   * The path passed to bind(). getsockname() returns the temporary path
+   * for sockets bound atomically so that we keep it here.
+   */
+  bound_path: RefCell<Option<String>>,
+  path_lock: RefCell<Option<PathLock>>,
+  unlink_path: RefCell<Option<BoundPath>>,
+  counters: RefCell<Counters>,
+  closed: Cell<bool>,
+  poll_events: Cell<i32>,
+  /**
+   * Whether SO_TIMESTAMPNS is enabled, i.e. "_data" events come with the
+   * time messages are received.
+   */
+  recv_timestamp: Cell<bool>,
+  /**
+   * Whether messages are being flushed. Sending from the callbacks of sent
+   * messages only enqueues messages so that the stack doesn't grow with them.
+   */
+  flushing: Cell<bool>,
+  /**
+   * Whether the socket closes once messages in the queue are sent.
+   */
+  close_when_flushed: Cell<bool>,
+}
+
+fn send_msg(fd: i32, bufs: &mut MsgBufs, addr: &mut sockaddr_un) -> i32 {
+  let mut msg = unsafe { mem::MaybeUninit::<msghdr>::zeroed().assume_init() };
+  let mut iovecs = bufs.iovecs();
+
+  msg.msg_iovlen = iovecs.len() as _;
+  msg.msg_iov = iovecs.as_mut_ptr();
+  msg.msg_name = addr as *mut sockaddr_un as *mut _;
+  msg.msg_namelen = mem::size_of::<sockaddr_un>() as u32;
+
+  let mut ret;
+  loop {
+    ret = unsafe { libc::sendmsg(fd, &mut msg as *mut _, 0) as i32 };
+
+    if !(ret == -1 && errno() == EINTR) {
+      break;
+    }
+  }
+
+  ret
 }
 
 /**
@@ -45,74 +115,134 @@ pub struct DgramSocketWrap {
 #[napi]
 impl DgramSocketWrap {
   #[napi(constructor)]
-  pub fn new(env: Env, ee: JsObject) -> Result<Self> {
+  pub fn new(env: Env, ee: JsObject, fd: Option<JsNumber>) -> Result<Self> {
     check_emit(&ee)?;
 
     let domain = libc::AF_UNIX;
     let ty = libc::SOCK_DGRAM;
     let protocol = 0;
-    let fd = unsafe { libc::socket(domain, ty, protocol) };
-
-    if fd == -1 {
-      return Err(get_err());
-    }
+    let fd: i32 = match fd {
+      Some(fd) => fd.get_int32()?,
+      None => {
+        let fd = unsafe { libc::socket(domain, ty, protocol) };
+        if fd == -1 {
+          return Err(get_err());
+        }
+        fd
+      }
+    };
 
     set_non_block(fd)?;
     set_clo_exec(fd)?;
 
-    let emit_fn: JsFunction = ee.get_named_property("emit")?;
+    let emitter = Emitter::new(env, &ee, "DgramSocket")?;
+    let uv_loop = get_loop(&env)?;
     let handle = Box::into_raw(Box::new(unsafe {
       let mut handle = mem::MaybeUninit::<sys::uv_poll_t>::zeroed().assume_init();
       handle.data = std::ptr::null_mut() as *mut _;
       handle
     }));
-    insert_handle(unsafe { mem::transmute(handle) })?;
+    if let Err(e) = resolve_uv_err(unsafe { sys::uv_poll_init(uv_loop, handle, fd) }) {
+      // libuv doesn't know the handle yet
+      drop(unsafe { Box::from_raw(handle) });
+      return Err(e);
+    }
+    // only register handles that can be passed to uv_close() in the clean
+    // up hook
+    if let Err(e) = insert_handle(env, handle as *mut _) {
+      unsafe { sys::uv_close(handle as *mut _, Some(on_close)) };
+      return Err(e);
+    }
 
     Ok(Self {
       fd,
-      handle,
-      msg_queue: LinkedList::new(),
+      handle: Cell::new(handle),
+      msg_queue: RefCell::new(LinkedList::new()),
       env,
-      emitter: Emitter::new(env, emit_fn)?,
+      emitter,
+      bound_path: RefCell::new(None),
+      path_lock: RefCell::new(None),
+      unlink_path: RefCell::new(None),
+      counters: RefCell::new(Counters::default()),
+      recv_timestamp: Cell::new(false),
+      closed: Cell::new(false),
+      poll_events: Cell::new(0),
+      flushing: Cell::new(false),
+      close_when_flushed: Cell::new(false),
     })
   }
 
   #[napi]
-  pub fn init(&mut self, env: Env, this_obj: JsObject) -> Result<()> {
+  pub fn init(&self, env: Env, this_obj: JsObject) -> Result<()> {
+    let handle = self.handle.get();
     let data = Box::into_raw(Box::new(HandleData::new(env, this_obj)?));
     unsafe {
-      (*self.handle).data = data as *mut _;
+      (*handle).data = data as *mut _;
     }
+    set_owner(env, handle as *mut _, self as *const Self)?;
 
-    Ok(())
+    self.update_refs()
   }
 
   #[napi]
-  pub fn start_recv(&mut self, env: Env) -> Result<()> {
-    let uv_loop = get_loop(&env)?;
+  pub fn start_recv(&self) -> Result<()> {
+    self.set_poll_events(self.poll_events.get() | uv_poll_event::UV_READABLE as i32)
+  }
 
-    unsafe {
-      resolve_uv_err(sys::uv_poll_init(uv_loop, self.handle, self.fd))?;
-      resolve_uv_err(sys::uv_poll_start(
-        self.handle,
-        sys::uv_poll_event::UV_READABLE as i32,
-        Some(on_event),
-      ))?;
+  #[napi]
+  pub fn stop_recv(&self) -> Result<()> {
+    self.set_poll_events(self.poll_events.get() & !(uv_poll_event::UV_READABLE as i32))
+  }
+
+  fn set_poll_events(&self, events: i32) -> Result<()> {
+    self.poll_events.set(events);
+    self.reset_poll()
+  }
+
+  fn reset_poll(&self) -> Result<()> {
+    let handle = self.handle.get();
+    if handle.is_null() || unsafe { sys::uv_is_closing(handle as *mut _) } != 0 {
+      return Ok(());
     }
 
-    Ok(())
+    let events = self.poll_events.get();
+    if events == 0 {
+      resolve_uv_err(unsafe { sys::uv_poll_stop(handle) })?;
+    } else {
+      resolve_uv_err(unsafe { sys::uv_poll_start(handle, events, Some(on_event)) })?;
+    }
+
+    self.update_refs()
+  }
+
+  fn update_refs(&self) -> Result<()> {
+    socket::update_refs(self.handle.get(), &self.emitter)
+  }
+
+  /**
+   * Pass the handle to uv_close(). It's freed in on_close() and the wrap
+   * never touches it again.
+   */
+  fn close_handle(&self) -> Result<()> {
+    let handle = self.handle.replace(ptr::null_mut());
+    // the clean up hook of the environment might have closed it
+    if unsafe { sys::uv_is_closing(handle as *mut _) } == 0 {
+      unsafe { sys::uv_close(handle as *mut _, Some(on_close)) };
+    }
+    remove_handle(self.env, handle as *mut _)
+  }
+
+  fn is_closed(&self) -> bool {
+    self.closed.get()
   }
 
   #[napi]
-  pub fn bind(&self, bindpath: String) -> Result<()> {
-    unsafe {
-      let sockaddr = sockaddr_from_string(&bindpath)?;
-      resolve_libc_err(libc::bind(
-        self.fd,
-        &sockaddr as *const _ as *const sockaddr,
-        mem::size_of::<sockaddr_un>() as u32,
-      ))?;
-    };
+  pub fn bind(&self, bindpath: String, options: Option<BindOptions>) -> Result<()> {
+    let options = options.unwrap_or_default();
+
+    *self.path_lock.borrow_mut() = bind_with_options(self.fd, &bindpath, &options, || Ok(()))?;
+    *self.unlink_path.borrow_mut() = BoundPath::from_options(&bindpath, &options);
+    *self.bound_path.borrow_mut() = Some(bindpath);
 
     Ok(())
   }
@@ -120,7 +250,10 @@ impl DgramSocketWrap {
   #[napi]
   pub fn address(&self, env: Env) -> Result<JsString> {
     let str = socket_addr_to_string(self.fd)?;
-    env.create_string(&str)
+    match self.bound_path.borrow().as_ref() {
+      Some(bound_path) => env.create_string(bound_path),
+      None => env.create_string(&str),
+    }
   }
 
   #[napi]
@@ -187,67 +320,157 @@ impl DgramSocketWrap {
     Ok(())
   }
 
-  fn flush(&mut self) -> Result<()> {
+  #[napi]
+  pub fn set_recv_timestamp(&self, enabled: bool) -> Result<()> {
+    timestamp::set_recv_timestamp(self.fd, enabled)?;
+    self.recv_timestamp.set(enabled);
+    Ok(())
+  }
+
+  #[napi]
+  pub fn get_recv_timestamp(&self) -> bool {
+    self.recv_timestamp.get()
+  }
+
+  #[napi]
+  pub fn stats(&self) -> SocketStats {
+    self.counters.borrow().snapshot()
+  }
+
+  #[napi]
+  pub fn get_unread_bytes(&self) -> Result<u32> {
+    queue::unread_bytes(self.fd)
+  }
+
+  #[napi]
+  pub fn get_unsent_bytes(&self) -> Result<u32> {
+    queue::unsent_bytes(self.fd)
+  }
+
+  #[napi]
+  pub fn get_next_datagram_size(&self) -> Result<Option<u32>> {
+    queue::next_datagram_size(self.fd)
+  }
+
+  #[napi]
+  pub fn peek(&self, env: Env, size: Option<u32>) -> Result<Option<JsBuffer>> {
+    match queue::peek(self.fd, size)? {
+      Some(data) => Ok(Some(env.create_buffer_with_data(data)?.into_raw())),
+      None => Ok(None),
+    }
+  }
+
+  #[napi]
+  pub fn get_sock_opt(&self, env: Env, name: String) -> Result<JsUnknown> {
+    sockopt::get_sock_opt(env, self.fd, &name)
+  }
+
+  #[napi]
+  pub fn set_sock_opt(&self, env: Env, name: String, value: JsUnknown) -> Result<()> {
+    sockopt::set_sock_opt(env, self.fd, &name, value)
+  }
+
+  #[napi]
+  pub fn get_raw_sock_opt(
+    &self,
+    env: Env,
+    level: i32,
+    optname: i32,
+    length: u32,
+  ) -> Result<JsBuffer> {
+    sockopt::get_raw_sock_opt(env, self.fd, level, optname, length)
+  }
+
+  #[napi]
+  pub fn set_raw_sock_opt(
+    &self,
+    env: Env,
+    level: i32,
+    optname: i32,
+    value: JsBuffer,
+  ) -> Result<()> {
+    sockopt::set_raw_sock_opt(env, self.fd, level, optname, value)
+  }
+
+  /**
+   * Exceptions thrown by callbacks go to `cb_errors` if there is a js caller
+   * on the stack, e.g. sendTo() sending the message right away, or to
+   * `uncaughtException` otherwise.
+   */
+  fn flush(&self, cb_errors: Option<&mut FirstError>) -> Result<()> {
+    if self.flushing.get() {
+      return Ok(());
+    }
+
+    self.flushing.set(true);
+    let ret = self.flush_queue(cb_errors);
+    self.flushing.set(false);
+
+    ret
+  }
+
+  fn flush_queue(&self, mut cb_errors: Option<&mut FirstError>) -> Result<()> {
     let env = self.env;
     loop {
-      let item = self.msg_queue.pop_front();
+      let item = self.msg_queue.borrow_mut().pop_front();
       if item.is_none() {
         break;
       }
       let mut item = item.unwrap();
-      let mut msg = unsafe { mem::MaybeUninit::<msghdr>::zeroed().assume_init() };
-      let mut iov = unsafe { mem::MaybeUninit::<iovec>::zeroed().assume_init() };
       let len = item.msg.len();
+      let ret = send_msg(self.fd, &mut item.msg, &mut item.sockaddr);
 
-      iov.iov_base = item.msg.as_mut_ptr() as *mut _;
-      iov.iov_len = len;
-
-      msg.msg_iovlen = 1;
-      msg.msg_iov = &mut iov as *mut _;
-      msg.msg_name = &mut item.sockaddr as *mut sockaddr_un as *mut _;
-      msg.msg_namelen = mem::size_of::<sockaddr_un>() as u32;
-
-      let mut ret;
-      loop {
-        ret = unsafe { libc::sendmsg(self.fd, &mut msg as *mut _, 0) as i32 };
-
-        if !(ret == -1 && errno() == EINTR) {
-          break;
-        }
-      }
-
-      let mut args: Vec<JsUnknown> = vec![];
+      let mut failed = None;
       if ret == -1 {
         let err = errno();
         if err == EAGAIN || err == EWOULDBLOCK || err == ENOBUFS {
-          self.msg_queue.push_front(item);
+          self.counters.borrow_mut().retry(err);
+          self.msg_queue.borrow_mut().push_front(item);
           break;
         }
+        self.counters.borrow_mut().dropped(len);
         // TODO is this a unrecoverable error?
-        let err = self.env.create_error(get_err())?;
-        args.push(err.into_unknown());
+        failed = Some(err);
+      } else {
+        let mut counters = self.counters.borrow_mut();
+        counters.written(ret as usize);
+        counters.sent(item.enqueued_at);
       }
 
       // call callbacks
-      if item.cb.is_some() {
-        let cb_ref = item.cb.as_mut().take().unwrap();
-        let cb: JsFunction = env.get_reference_value(&cb_ref)?;
-        let _ = cb.call(None, &args).map_err(|e| {
-          let _ = self.env.throw_error(&e.reason, None);
-        });
-        cb_ref.unref(self.env)?;
+      if let Some(cb) = item.cb.take() {
+        let ret = socket::call_write_cb(env, &self.emitter, cb, failed);
+        match cb_errors.as_deref_mut() {
+          Some(cb_errors) => cb_errors.check(ret),
+          None => {
+            if let Err(e) = ret {
+              self.emit_error(e);
+            }
+          }
+        }
+      }
+
+      // callbacks might close the socket
+      if self.is_closed() {
+        return Ok(());
       }
     }
 
-    // poll writable if there are messages
-    if self.msg_queue.len() > 0 {
-      unsafe {
-        resolve_uv_err(sys::uv_poll_start(
-          self.handle,
-          sys::uv_poll_event::UV_WRITABLE as i32,
-          Some(on_event),
-        ))?;
-      };
+    let is_empty = self.msg_queue.borrow().is_empty();
+    if is_empty && self.close_when_flushed.get() {
+      return self.close(env);
+    }
+
+    // poll writable only if there are messages
+    let writable = uv_poll_event::UV_WRITABLE as i32;
+    let poll_events = self.poll_events.get();
+    let events = if is_empty {
+      poll_events & !writable
+    } else {
+      poll_events | writable
+    };
+    if events != poll_events {
+      self.set_poll_events(events)?;
     }
 
     Ok(())
@@ -255,7 +478,7 @@ impl DgramSocketWrap {
 
   #[napi]
   pub fn send_to(
-    &mut self,
+    &self,
     env: Env,
     buf: JsBuffer,
     offset: JsNumber,
@@ -263,196 +486,464 @@ impl DgramSocketWrap {
     path: String,
     cb: Option<JsFunction>,
   ) -> Result<()> {
+    if self.is_closed() {
+      return Err(error("socket has been closed".to_string()));
+    }
+    if self.close_when_flushed.get() {
+      return Err(error("socket is closing".to_string()));
+    }
     let offset = offset.get_int32()?;
     let length = length.get_int32()?;
-    let end = offset + length;
-    let offset = offset;
-    let end = end;
-    let msg = buf_into_vec(buf, offset, end)?;
+    let msg = buf_into_vec(env, buf, offset, length)?;
+    self.enqueue(env, msg.into(), path, cb)
+  }
+
+  /**
+   * Send the buffers together as one datagram without concatenating them.
+   */
+  #[napi]
+  pub fn sendv_to(
+    &self,
+    env: Env,
+    bufs: Vec<JsBuffer>,
+    path: String,
+    cb: Option<JsFunction>,
+  ) -> Result<()> {
+    if self.is_closed() {
+      return Err(error("socket has been closed".to_string()));
+    }
+    if self.close_when_flushed.get() {
+      return Err(error("socket is closing".to_string()));
+    }
+    let msg = MsgBufs::from_js(env, bufs)?;
+    self.enqueue(env, msg, path, cb)
+  }
 
+  fn enqueue(&self, env: Env, msg: MsgBufs, path: String, cb: Option<JsFunction>) -> Result<()> {
     let (addr, _) = sockaddr_from_string(&path)?;
     let cb = match cb {
       None => None,
       Some(cb) => Some(env.create_reference(cb)?),
     };
 
+    self.counters.borrow_mut().enqueue(msg.len());
     let m = MsgInfoItem {
       sockaddr: addr,
       msg,
       cb,
+      enqueued_at: Instant::now(),
     };
 
-    self.msg_queue.push_back(m);
+    self.msg_queue.borrow_mut().push_back(m);
 
-    self.flush()?;
+    // callbacks of messages sent right away throw to the caller
+    let mut cb_errors = FirstError::new(env);
+    let ret = self.flush(Some(&mut cb_errors));
+    cb_errors.check(ret);
+    cb_errors.into_result()
+  }
 
-    Ok(())
+  /**
+   * Send messages in the queue with poll() instead of the event loop, e.g.
+   * in "exit" listeners. Their callbacks are called before it returns and
+   * the first exception they throw is thrown.
+   */
+  #[napi]
+  pub fn flush_sync(&self, timeout: Option<u32>) -> Result<()> {
+    if self.is_closed() {
+      return Err(error("socket has been closed".to_string()));
+    }
+    self.flush_until(blocking::deadline(timeout), "flushSync")
   }
 
+  fn flush_until(&self, deadline: Option<Instant>, op: &str) -> Result<()> {
+    if self.flushing.get() {
+      return Err(error(format!("can't call {} in callbacks of sendTo()", op)));
+    }
+
+    let mut cb_errors = FirstError::new(self.env);
+    let ret = (|| loop {
+      self.flush(Some(&mut cb_errors))?;
+      if self.is_closed() || self.msg_queue.borrow().is_empty() {
+        return Ok(());
+      }
+      if !blocking::wait_fd(self.fd, libc::POLLOUT, deadline)? {
+        return Err(blocking::timed_out(op));
+      }
+    })();
+    // exceptions of callbacks are thrown before errors of sending
+    cb_errors.check(ret);
+    cb_errors.into_result()
+  }
+
+  /**
+   * Send a message with poll() instead of the event loop after the queue is
+   * flushed. The message is dropped if it times out.
+   */
   #[napi]
-  pub fn close(&mut self, env: Env) -> Result<()> {
-    // stop watcher
-    let is_closing = unsafe { sys::uv_is_closing(self.handle as *mut _) } != 0;
-    if !is_closing {
-      resolve_uv_err(unsafe { sys::uv_poll_stop(self.handle) })?;
+  pub fn send_sync(
+    &self,
+    env: Env,
+    buf: JsBuffer,
+    offset: JsNumber,
+    length: JsNumber,
+    path: String,
+    timeout: Option<u32>,
+  ) -> Result<()> {
+    if self.is_closed() {
+      return Err(error("socket has been closed".to_string()));
+    }
+    if self.close_when_flushed.get() {
+      return Err(error("socket is closing".to_string()));
+    }
+    let offset = offset.get_int32()?;
+    let length = length.get_int32()?;
+    let mut msg: MsgBufs = buf_into_vec(env, buf, offset, length)?.into();
+    let (mut addr, _) = sockaddr_from_string(&path)?;
+    let deadline = blocking::deadline(timeout);
+    self.flush_until(deadline, "sendSync")?;
+    if self.is_closed() {
+      return Err(error("socket has been closed".to_string()));
     }
-    unsafe {
-      let handle = mem::transmute(self.handle);
-      sys::uv_close(handle, Some(on_close));
-      remove_handle(handle)?;
-    };
 
-    // release Ref<JsFunction> in msg_queue
+    let size = msg.len();
+    let enqueued_at = Instant::now();
+    self.counters.borrow_mut().enqueue(size);
     loop {
-      let msg = self.msg_queue.pop_front();
-      if msg.is_none() {
-        break;
+      let ret = send_msg(self.fd, &mut msg, &mut addr);
+      if ret >= 0 {
+        let mut counters = self.counters.borrow_mut();
+        counters.written(ret as usize);
+        counters.sent(enqueued_at);
+        return Ok(());
       }
 
-      let mut msg = msg.unwrap();
-      let mut cb = msg.cb.take();
-      match cb.as_mut() {
-        None => (),
-        Some(cb) => {
-          cb.unref(env)?;
+      let err = errno();
+      if err == EAGAIN || err == EWOULDBLOCK || err == ENOBUFS {
+        self.counters.borrow_mut().retry(err);
+        if blocking::wait_fd(self.fd, libc::POLLOUT, deadline)? {
+          continue;
         }
+        self.counters.borrow_mut().dropped(size);
+        return Err(blocking::timed_out("sendSync"));
       }
+
+      let e = get_err();
+      self.counters.borrow_mut().dropped(size);
+      return Err(e);
     }
+  }
 
-    close(self.fd)?;
+  /**
+   * Read a datagram with poll() instead of the event loop. Returns
+   * `{ buf, path }` and throws if it times out.
+   */
+  #[napi]
+  pub fn recv_sync(&self, env: Env, timeout: Option<u32>) -> Result<JsObject> {
+    if self.is_closed() {
+      return Err(error("socket has been closed".to_string()));
+    }
+    let deadline = blocking::deadline(timeout);
+    loop {
+      if let Some(msg) = self.recv_msg()? {
+        let mut obj = env.create_object()?;
+        obj.set_named_property("buf", env.create_buffer_with_data(msg.buf)?.into_raw())?;
+        obj.set_named_property("path", env.create_string(&msg.path)?)?;
+        return Ok(obj);
+      }
+      if !blocking::wait_fd(self.fd, libc::POLLIN, deadline)? {
+        return Err(blocking::timed_out("recvSync"));
+      }
+    }
+  }
 
-    let event = env.create_string("close")?;
-    self.emitter.emit(&[event.into_unknown()])?;
+  /**
+   * Stop polling and release the uv handle without closing the fd. Returns
+   * the fd so that it can be adopted by another wrap, e.g. in a worker.
+   *
+   * Queued messages aren't handed over so that it refuses to detach until
+   * they are flushed. `unlink_on_teardown` isn't handed over either and the
+   * path is never unlinked after it.
+   */
+  #[napi]
+  pub fn detach(&self) -> Result<i32> {
+    if self.is_closed() {
+      return Err(error("socket has been closed".to_string()));
+    }
+    if !self.msg_queue.borrow().is_empty() {
+      return Err(error(
+        "can't detach a socket with pending messages".to_string(),
+      ));
+    }
+    if self.path_lock.borrow().is_some() {
+      return Err(error(
+        "can't detach a socket holding a path lock".to_string(),
+      ));
+    }
+
+    self.set_poll_events(0)?;
+    self.close_handle()?;
+    self.unlink_path.take();
+
+    self.closed.set(true);
     self.emitter.unref()?;
 
+    Ok(self.fd)
+  }
+
+  /**
+   * Close the socket once messages in the queue are sent and their callbacks
+   * are called. Messages can't be sent after it.
+   */
+  #[napi]
+  pub fn close_when_flushed(&self, env: Env) -> Result<()> {
+    if self.is_closed() || self.close_when_flushed.get() {
+      return Ok(());
+    }
+    self.close_when_flushed.set(true);
+
+    if self.msg_queue.borrow().is_empty() && !self.flushing.get() {
+      return self.close(env);
+    }
+    // else close when msgs flushed
     Ok(())
   }
 
-  fn read_data(&mut self) -> Result<()> {
-    let s = self;
+  #[napi]
+  pub fn close(&self, env: Env) -> Result<()> {
+    if self.is_closed() {
+      return Ok(());
+    }
+
+    let cbs = self.release()?;
+    // "close" is emitted even if callbacks throw, and the first exception is
+    // thrown after it
+    let mut first_error = socket::cancel_write_cbs(env, &self.emitter, cbs);
+    first_error.check(self.emitter.emit_event("close"));
+    first_error.check(self.emitter.unref());
+
+    first_error.into_result()
+  }
+
+  /**
+   * Stop polling, drop messages waiting to be sent and close the fd.
+   * Returns callbacks of the dropped messages.
+   */
+  fn release(&self) -> Result<Vec<Ref<()>>> {
+    self.closed.set(true);
+
+    // stop watcher
+    self.set_poll_events(0)?;
+    self.close_handle()?;
+
+    // release Ref<JsFunction> in msg_queue
+    let msgs = mem::take(&mut *self.msg_queue.borrow_mut());
+    let mut cbs = vec![];
+    for mut msg in msgs {
+      self.counters.borrow_mut().dropped(msg.msg.len());
+      if let Some(cb) = msg.cb.take() {
+        cbs.push(cb);
+      }
+    }
+
+    // release the lock only after the fd is closed so that the next owner
+    // of the path never races with this socket
+    let ret = close(self.fd);
+    self.path_lock.take();
+    ret?;
+
+    Ok(cbs)
+  }
+
+  /**
+   * Read a datagram with recvmsg(). Returns `None` if there is no datagram
+   * to read.
+   */
+  fn recv_msg(&self) -> Result<Option<RecvMsg>> {
+    let mut msg = unsafe { mem::MaybeUninit::<msghdr>::zeroed().assume_init() };
+    let cap = 65535;
+    let mut base = vec![0; cap];
+    let base_ptr = base.as_mut_ptr();
+
+    let mut iov = libc::iovec {
+      iov_base: base_ptr as *mut _,
+      iov_len: cap,
+    };
+
+    let mut name = unsafe { mem::MaybeUninit::<sockaddr_un>::zeroed().assume_init() };
+    let name_len = mem::size_of::<sockaddr_un>();
+    msg.msg_iovlen = 1;
+    msg.msg_iov = &mut iov as *mut _;
+    msg.msg_name = &mut name as *mut sockaddr_un as *mut _;
+    msg.msg_namelen = name_len as u32;
+    let mut cmsg_buf = CmsgBuf::new();
+    if self.recv_timestamp.get() {
+      cmsg_buf.attach(&mut msg);
+    }
+
+    let mut ret;
     loop {
-      let mut msg = unsafe { mem::MaybeUninit::<msghdr>::zeroed().assume_init() };
-      let cap = 65535;
-      let mut base = vec![0; cap];
-      let base_ptr = base.as_mut_ptr();
-
-      let mut iov = libc::iovec {
-        iov_base: base_ptr as *mut _,
-        iov_len: cap,
-      };
+      ret = unsafe { libc::recvmsg(self.fd, &mut msg as *mut _, 0) };
+      if !(ret == -1 && errno() == nix::Error::EINTR as i32) {
+        break;
+      }
+    }
 
-      let mut name = unsafe { mem::MaybeUninit::<sockaddr_un>::zeroed().assume_init() };
-      let name_len = mem::size_of::<sockaddr_un>();
-      msg.msg_iovlen = 1;
-      msg.msg_iov = &mut iov as *mut _;
-      msg.msg_name = &mut name as *mut sockaddr_un as *mut _;
-      msg.msg_namelen = name_len as u32;
-
-      let mut ret;
-      loop {
-        ret = unsafe { libc::recvmsg(s.fd, &mut msg as *mut _, 0) };
-        if !(ret == -1 && errno() == nix::Error::EINTR as i32) {
-          break;
-        }
+    if ret == -1 {
+      let err = errno();
+      if err == EAGAIN || err == EWOULDBLOCK || err == ENOBUFS {
+        return Ok(None);
       }
+      return Err(error(format!("recv msg failed, errno: {}", err)));
+    }
 
-      let mut args: Vec<JsUnknown> = vec![];
-      let env = s.env.clone();
+    let len = ret as usize;
+    self
+      .counters
+      .borrow_mut()
+      .received(len, msg.msg_flags & libc::MSG_TRUNC != 0);
+    let slice = base[0..len].to_vec();
+
+    let name = unsafe { *(msg.msg_name as *mut sockaddr_un) };
+    let ts = if self.recv_timestamp.get() {
+      timestamp::parse_recv_timestamp(&msg)
+    } else {
+      None
+    };
 
-      if ret == -1 {
-        let err = errno();
-        if err == EAGAIN || err == EWOULDBLOCK || err == ENOBUFS {
-          break;
-        }
-        let event = env.create_string("_error")?;
-        args.push(event.into_unknown());
-        let err = error(format!("recv msg failed, errno: {}", errno()));
-        let err = env.create_error(err)?;
-        args.push(err.into_unknown());
-      } else {
-        let len = ret as usize;
-        let slice = base[0..len].to_vec();
+    Ok(Some(RecvMsg {
+      buf: slice,
+      path: addr_to_string(&name),
+      timestamp: ts,
+    }))
+  }
 
-        let name = unsafe { *(msg.msg_name as *mut sockaddr_un) };
+  fn read_data(&self) -> Result<()> {
+    let s = self;
+    loop {
+      let msg = match s.recv_msg()? {
+        Some(msg) => msg,
+        None => break,
+      };
 
-        let js_sockname = {
-          let name = addr_to_string(&name);
-          env.create_string(&name)?
-        };
+      let mut args: Vec<JsUnknown> = vec![];
+      let env = s.env;
+      let js_sockname = env.create_string(&msg.path)?;
+
+      let buf = env.create_buffer_with_data(msg.buf)?;
+      let event = env.create_string("_data")?;
+      args.push(event.into_unknown());
+      args.push(buf.into_unknown());
+      args.push(js_sockname.into_unknown());
+      if s.recv_timestamp.get() {
+        args.push(timestamp::timestamp_to_js(env, msg.timestamp)?);
+      }
 
-        let buf = env.create_buffer_with_data(slice)?;
-        let event = env.create_string("_data")?;
-        args.push(event.into_unknown());
-        args.push(buf.into_unknown());
-        args.push(js_sockname.into_unknown());
+      // an exception thrown by listeners doesn't stop reading
+      if let Err(e) = s.emitter.emit(&args) {
+        s.emit_error(e);
       }
 
-      let _ = s.emitter.emit(&args).map_err(|e| {
-        let _ = env.throw_error(&e.reason, None);
-      });
+      // listeners might close the socket
+      if s.is_closed() {
+        break;
+      }
     }
 
     Ok(())
   }
 
-  fn handle_event(&mut self, status: i32, events: i32) {
+  fn handle_event(&self, status: i32, events: i32) {
     if status == sys::uv_errno_t::UV_ECANCELED as i32 {
       return;
     }
 
     let env = self.env;
-    env
-      .run_in_scope(|| {
-        if status != 0 {
-          let _ = env.throw_error(&format!("on_event receive error status: {}", status), None);
-          return Ok(());
-        }
+    let _ = env.run_in_scope(|| {
+      if status != 0 {
+        let err = error(format!("on_event receive error: {}", uv_err_msg(status)));
+        self.emit_error(err);
+        return Ok(());
+      }
 
-        if events & uv_poll_event::UV_READABLE as i32 != 0 {
-          self
-            .read_data()
-            .map_err(|e| {
-              let _ = env.throw_error(&e.reason, None);
-              e
-            })
-            .or::<napi::Error>(Ok(()))
-            .unwrap();
+      if events & uv_poll_event::UV_READABLE as i32 != 0 {
+        if let Err(e) = self.read_data() {
+          self.emit_error(e);
         }
+      }
 
-        if events & uv_poll_event::UV_WRITABLE as i32 != 0 {
-          self
-            .flush()
-            .map_err(|e| {
-              let _ = env.throw_error(&e.reason, None);
-              e
-            })
-            .or::<napi::Error>(Ok(()))
-            .unwrap();
+      if events & uv_poll_event::UV_WRITABLE as i32 != 0 && !self.is_closed() {
+        if let Err(e) = self.flush(None) {
+          self.emit_error(e);
         }
+      }
 
-        Ok(())
-      })
-      .unwrap();
+      Ok(())
+    });
+  }
+
+  fn emit_error(&self, error: napi::Error) {
+    socket::emit_error(self.env, &self.emitter, error);
   }
 }
 
 extern "C" fn on_event(handle: *mut sys::uv_poll_t, status: i32, events: i32) {
-  let handle = unsafe { Box::from_raw(handle) };
-  assert!(!handle.data.is_null(), "unexpected null handle data");
-  let data = unsafe { Box::from_raw(handle.data as *mut HandleData) };
+  // Only borrow the handle data here. Callbacks might close the socket and
+  // the data is freed in on_close().
+  let data = unsafe { (*handle).data as *const HandleData };
+  if data.is_null() {
+    return;
+  }
+  let data = unsafe { &*data };
 
-  let wrap: &mut DgramSocketWrap = data.inner_mut_ref().unwrap();
-  wrap.handle_event(status, events);
+  // the object can't be recovered if the environment is being torn down
+  if let Ok(wrap) = data.inner_ref::<&DgramSocketWrap>() {
+    wrap.handle_event(status, events);
+  }
+}
 
-  Box::into_raw(data);
-  Box::into_raw(handle);
+impl Teardown for DgramSocketWrap {
+  fn teardown(&self) {
+    if self.is_closed() {
+      return;
+    }
+
+    socket::release_handle_data(self.handle.get());
+    if let Ok(cbs) = self.release() {
+      socket::drop_write_cbs(self.env, cbs);
+    }
+    if let Some(path) = self.unlink_path.take() {
+      path.unlink();
+    }
+    let _ = self.emitter.unref();
+  }
+}
+
+/**
+ * Runs when the js object gets garbage-collected. Sockets are only
+ * collectable while they are not polling, see `socket::update_refs()`.
+ */
+impl Drop for DgramSocketWrap {
+  fn drop(&mut self) {
+    if self.is_closed() {
+      return;
+    }
+
+    socket::warn_unclosed(self.env, "DgramSocket", self.fd);
+    if let Ok(cbs) = self.release() {
+      socket::drop_write_cbs(self.env, cbs);
+    }
+  }
 }
 
 extern "C" fn on_close(handle: *mut sys::uv_handle_t) {
   unsafe {
-    let handle = Box::from_raw(handle);
-    assert!(!handle.data.is_null(), "unexpected null handle data");
-    let mut data = Box::from_raw(handle.data as *mut HandleData);
-    data.unref().unwrap();
+    // the data is null if init() is never called
+    let data = (*handle).data as *mut HandleData;
+    if !data.is_null() {
+      let data = Box::from_raw(data);
+      let _ = data.unref();
+    }
+    let _ = Box::from_raw(handle as *mut sys::uv_poll_t);
   };
 }
@@ -5,6 +5,12 @@ extern crate napi_derive;
 
 mod seqpacket;
 mod dgram;
+mod blocking;
+mod queue;
+mod reuseport;
 mod util;
 mod socket;
+mod sockopt;
+mod stats;
+mod timestamp;
 mod uv_handle;
@@ -0,0 +1,139 @@
+use crate::util::resolve_libc_err;
+use libc::{EAGAIN, EINTR, EWOULDBLOCK};
+use napi::Result;
+use nix::errno::errno;
+
+/**
+ * The bytes waiting to be read in the receive queue, i.e. SIOCINQ/FIONREAD.
+ * On Linux, it's the size of the next datagram for SOCK_DGRAM sockets.
+ */
+pub(crate) fn unread_bytes(fd: i32) -> Result<u32> {
+  let mut val: libc::c_int = 0;
+  resolve_libc_err(unsafe { libc::ioctl(fd, libc::FIONREAD, &mut val as *mut _) })?;
+  Ok(val as u32)
+}
+
+/**
+ * The bytes in the send queue that haven't been consumed by the peer, i.e.
+ * SIOCOUTQ. Linux counts the memory of unix socket messages including their
+ * overhead.
+ */
+#[cfg(target_os = "linux")]
+pub(crate) fn unsent_bytes(fd: i32) -> Result<u32> {
+  // SIOCOUTQ is the same as TIOCOUTQ
+  let mut val: libc::c_int = 0;
+  resolve_libc_err(unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut val as *mut _) })?;
+  Ok(val as u32)
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn unsent_bytes(fd: i32) -> Result<u32> {
+  Ok(get_int_sockopt(fd, libc::SO_NWRITE)? as u32)
+}
+
+/**
+ * The size of the next datagram in the receive queue. Returns `None` if
+ * there is no datagram.
+ */
+#[cfg(target_os = "linux")]
+pub(crate) fn next_datagram_size(fd: i32) -> Result<Option<u32>> {
+  let flags = libc::MSG_PEEK | libc::MSG_TRUNC | libc::MSG_DONTWAIT;
+  let mut ret;
+  loop {
+    // MSG_TRUNC makes recv() return the real length of the datagram
+    ret = unsafe { libc::recv(fd, std::ptr::null_mut(), 0, flags) };
+    if !(ret == -1 && errno() == EINTR) {
+      break;
+    }
+  }
+
+  if ret == -1 {
+    let err = errno();
+    if err == EAGAIN || err == EWOULDBLOCK {
+      return Ok(None);
+    }
+    resolve_libc_err(-1)?;
+  }
+
+  Ok(Some(ret as u32))
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn next_datagram_size(fd: i32) -> Result<Option<u32>> {
+  // SO_NREAD returns the size of the first datagram
+  let size = get_int_sockopt(fd, libc::SO_NREAD)? as u32;
+  if size == 0 && unread_bytes(fd)? == 0 {
+    return Ok(None);
+  }
+
+  Ok(Some(size))
+}
+
+/**
+ * Copy the next message in the receive queue without removing it, i.e.
+ * MSG_PEEK. It copies the whole message unless `size` is given. With
+ * SO_PEEK_OFF set, the kernel starts copying at the peek offset and moves it
+ * forward by the bytes copied so that successive peeks step through the
+ * queued messages. Returns `None` if there is no message.
+ */
+pub(crate) fn peek(fd: i32, size: Option<u32>) -> Result<Option<Vec<u8>>> {
+  let size = match size {
+    Some(size) => size,
+    None => match next_datagram_size(fd)? {
+      Some(size) => size,
+      None => return Ok(None),
+    },
+  };
+
+  let mut buf: Vec<u8> = vec![0; size as usize];
+  let flags = libc::MSG_PEEK | libc::MSG_DONTWAIT;
+  let mut ret;
+  loop {
+    ret = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut _, buf.len(), flags) };
+    if !(ret == -1 && errno() == EINTR) {
+      break;
+    }
+  }
+
+  if ret == -1 {
+    let err = errno();
+    if err == EAGAIN || err == EWOULDBLOCK {
+      return Ok(None);
+    }
+    resolve_libc_err(-1)?;
+  }
+
+  buf.truncate(ret as usize);
+  Ok(Some(buf))
+}
+
+/**
+ * The largest message that the socket can send in one piece. Linux rejects
+ * messages of SOCK_SEQPACKET and SOCK_DGRAM unix sockets that are larger
+ * than the send buffer minus 32 bytes with EMSGSIZE.
+ */
+#[cfg(target_os = "linux")]
+pub(crate) fn max_msg_size(fd: i32) -> Result<u32> {
+  let sndbuf = get_int_sockopt(fd, libc::SO_SNDBUF)?;
+  Ok((sndbuf - 32).max(0) as u32)
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn max_msg_size(fd: i32) -> Result<u32> {
+  Ok(get_int_sockopt(fd, libc::SO_SNDBUF)?.max(0) as u32)
+}
+
+fn get_int_sockopt(fd: i32, name: i32) -> Result<i32> {
+  let mut val: libc::c_int = 0;
+  let mut len = std::mem::size_of::<libc::c_int>() as u32;
+  resolve_libc_err(unsafe {
+    libc::getsockopt(
+      fd,
+      libc::SOL_SOCKET,
+      name,
+      &mut val as *mut _ as *mut libc::c_void,
+      &mut len as *mut _,
+    )
+  })?;
+  Ok(val)
+}
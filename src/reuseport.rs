@@ -0,0 +1,479 @@
+use crate::socket::{
+  self, inet_socket_address, new_reuseport_socket, set_int_sockopt, AddressInfo,
+};
+use crate::sockopt;
+use crate::util::{error, resolve_libc_err};
+use napi::{Env, JsBuffer, JsUnknown, Result};
+
+const DEFAULT_BACKLOG: i32 = 511;
+
+/**
+ * An instruction of a classic BPF program, i.e. `struct sock_filter`.
+ */
+#[napi(object)]
+pub struct BpfInstruction {
+  pub code: u32,
+  pub jt: u32,
+  pub jf: u32,
+  pub k: u32,
+}
+
+/**
+ * Options of creating SO_REUSEPORT enabled sockets.
+ */
+#[napi(object)]
+#[derive(Default)]
+pub struct ReuseportOptions {
+  /**
+   * Attach a built-in SO_ATTACH_REUSEPORT_CBPF program. "cpu" selects the
+   * socket by the CPU that receives the packet while "hash" selects it by
+   * the rx hash of the flow. TCP sockets are put into the listening state
+   * before attaching programs.
+   */
+  pub steering: Option<String>,
+  /**
+   * The number of sockets in the reuseport group. It's required by
+   * `steering` as the built-in programs return `index % groupSize`.
+   */
+  pub group_size: Option<u32>,
+  /**
+   * A custom classic BPF program attached with SO_ATTACH_REUSEPORT_CBPF. The
+   * program returns the index of the socket in the group.
+   */
+  pub program: Option<Vec<BpfInstruction>>,
+  /**
+   * Set SO_INCOMING_CPU.
+   */
+  pub incoming_cpu: Option<i32>,
+  /**
+   * Set IPV6_V6ONLY. Only for IPv6 sockets.
+   */
+  pub ipv6_only: Option<bool>,
+  /**
+   * Set IP_FREEBIND to bind to addresses that don't exist yet.
+   */
+  pub freebind: Option<bool>,
+  /**
+   * Set SO_BINDTODEVICE, e.g. "lo".
+   */
+  pub bind_to_device: Option<String>,
+  /**
+   * Set TCP_DEFER_ACCEPT in seconds. Only for TCP sockets.
+   */
+  pub defer_accept: Option<i32>,
+  /**
+   * Set the queue length of TCP_FASTOPEN. Only for TCP sockets.
+   */
+  pub fast_open: Option<i32>,
+  /**
+   * Call listen() on the socket. Only for TCP sockets.
+   */
+  pub listen: Option<bool>,
+  /**
+   * The backlog passed to listen(). Defaults to 511.
+   */
+  pub backlog: Option<i32>,
+}
+
+#[derive(Eq, PartialEq, Copy, Clone)]
+enum ListenerState {
+  /**
+   * The listener owns the fd.
+   */
+  Bound = 1,
+  /**
+   * The fd has been handed off and is owned by others, e.g. net.Server.
+   */
+  HandedOff = 2,
+  Closed = 3,
+}
+
+/**
+ * A TCP socket with SO_REUSEPORT enabled. The fd is closed when the listener
+ * gets garbage collected unless it has been handed off by `hand_off()`.
+ */
+#[napi]
+pub struct ReuseportListener {
+  fd: i32,
+  state: ListenerState,
+}
+
+#[napi]
+impl ReuseportListener {
+  #[napi(constructor)]
+  pub fn new(
+    domain: String,
+    port: u32,
+    ip: String,
+    options: Option<ReuseportOptions>,
+  ) -> Result<Self> {
+    let options = options.unwrap_or_default();
+    let fd = new_reuseport_socket(&domain, libc::SOCK_STREAM, port as i32, &ip, &options)?;
+
+    Ok(Self {
+      fd,
+      state: ListenerState::Bound,
+    })
+  }
+
+  fn check_closed(&self) -> Result<()> {
+    if self.state == ListenerState::Closed {
+      return Err(error("listener closed".to_string()));
+    }
+
+    Ok(())
+  }
+
+  /**
+   * The fd of the listener. It's still owned by the listener, see
+   * `hand_off()`.
+   */
+  #[napi(getter)]
+  pub fn fd(&self) -> Result<i32> {
+    self.check_closed()?;
+    Ok(self.fd)
+  }
+
+  /**
+   * Hand off the fd, e.g. to net.Server, so that it won't be closed by
+   * `close()` or when the listener gets garbage collected. Returns the fd.
+   */
+  #[napi]
+  pub fn hand_off(&mut self) -> Result<i32> {
+    self.check_closed()?;
+    self.state = ListenerState::HandedOff;
+    Ok(self.fd)
+  }
+
+  #[napi]
+  pub fn address(&self) -> Result<AddressInfo> {
+    self.check_closed()?;
+    inet_socket_address(self.fd)
+  }
+
+  #[napi]
+  pub fn listen(&self, backlog: Option<i32>) -> Result<()> {
+    self.check_closed()?;
+    let backlog = backlog.unwrap_or(DEFAULT_BACKLOG);
+    resolve_libc_err(unsafe { libc::listen(self.fd, backlog) })?;
+    Ok(())
+  }
+
+  #[napi]
+  pub fn get_sock_opt(&self, env: Env, name: String) -> Result<JsUnknown> {
+    self.check_closed()?;
+    sockopt::get_sock_opt(env, self.fd, &name)
+  }
+
+  #[napi]
+  pub fn set_sock_opt(&self, env: Env, name: String, value: JsUnknown) -> Result<()> {
+    self.check_closed()?;
+    sockopt::set_sock_opt(env, self.fd, &name, value)
+  }
+
+  #[napi]
+  pub fn get_raw_sock_opt(
+    &self,
+    env: Env,
+    level: i32,
+    optname: i32,
+    length: u32,
+  ) -> Result<JsBuffer> {
+    self.check_closed()?;
+    sockopt::get_raw_sock_opt(env, self.fd, level, optname, length)
+  }
+
+  #[napi]
+  pub fn set_raw_sock_opt(
+    &self,
+    env: Env,
+    level: i32,
+    optname: i32,
+    value: JsBuffer,
+  ) -> Result<()> {
+    self.check_closed()?;
+    sockopt::set_raw_sock_opt(env, self.fd, level, optname, value)
+  }
+
+  #[napi]
+  pub fn close(&mut self) -> Result<()> {
+    let state = self.state;
+    self.state = ListenerState::Closed;
+    match state {
+      ListenerState::Bound => socket::close(self.fd),
+      // the fd isn't ours anymore
+      ListenerState::HandedOff | ListenerState::Closed => Ok(()),
+    }
+  }
+}
+
+impl Drop for ReuseportListener {
+  fn drop(&mut self) {
+    if self.state == ListenerState::Bound {
+      let _ = socket::close(self.fd);
+    }
+  }
+}
+
+pub(crate) fn has_program(options: &ReuseportOptions) -> bool {
+  options.steering.is_some() || options.program.is_some()
+}
+
+fn check_tcp_only(ty: i32, options: &ReuseportOptions) -> Result<()> {
+  if ty == libc::SOCK_STREAM {
+    return Ok(());
+  }
+
+  let tcp_only = [
+    ("deferAccept", options.defer_accept.is_some()),
+    ("fastOpen", options.fast_open.is_some()),
+    ("listen", options.listen.is_some()),
+    ("backlog", options.backlog.is_some()),
+  ];
+  for (name, is_set) in tcp_only {
+    if is_set {
+      return Err(error(format!(
+        "'{}' is only supported by TCP sockets",
+        name
+      )));
+    }
+  }
+
+  Ok(())
+}
+
+/**
+ * Apply options that should be set before bind().
+ */
+pub(crate) fn before_bind(fd: i32, domain: i32, ty: i32, options: &ReuseportOptions) -> Result<()> {
+  check_tcp_only(ty, options)?;
+
+  if let Some(ipv6_only) = options.ipv6_only {
+    if domain != libc::AF_INET6 {
+      return Err(error(
+        "'ipv6Only' is only supported by IPv6 sockets".to_string(),
+      ));
+    }
+    set_int_sockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, ipv6_only as i32)?;
+  }
+
+  if let Some(freebind) = options.freebind {
+    set_freebind(fd, freebind)?;
+  }
+
+  if let Some(device) = options.bind_to_device.as_deref() {
+    bind_to_device(fd, device)?;
+  }
+
+  Ok(())
+}
+
+/**
+ * Apply options to a bound socket and call listen() if it's required.
+ * Attaching a program replaces the one of the whole reuseport group.
+ */
+pub(crate) fn after_bind(fd: i32, ty: i32, options: &ReuseportOptions) -> Result<()> {
+  if ty == libc::SOCK_STREAM {
+    if let Some(defer_accept) = options.defer_accept {
+      set_defer_accept(fd, defer_accept)?;
+    }
+
+    if let Some(qlen) = options.fast_open {
+      set_int_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, qlen)?;
+    }
+
+    // A TCP socket that owns a program can't join an existing reuseport group
+    // in listen() so that we attach programs to listening sockets.
+    if options.listen.unwrap_or(false) || has_program(options) {
+      let backlog = options.backlog.unwrap_or(DEFAULT_BACKLOG);
+      resolve_libc_err(unsafe { libc::listen(fd, backlog) })?;
+    }
+  }
+
+  if let Some(cpu) = options.incoming_cpu {
+    set_incoming_cpu(fd, cpu)?;
+  }
+
+  let program = match (options.steering.as_deref(), options.program.as_ref()) {
+    (None, None) => return Ok(()),
+    (Some(_), Some(_)) => {
+      return Err(error(
+        "'steering' and 'program' can't be used together".to_string(),
+      ))
+    }
+    (Some(steering), None) => preset_program(steering, options.group_size)?,
+    (None, Some(program)) => program
+      .iter()
+      .map(|ins| (ins.code, ins.jt, ins.jf, ins.k))
+      .collect(),
+  };
+
+  attach_cbpf(fd, &program)
+}
+
+#[cfg(target_os = "linux")]
+fn set_freebind(fd: i32, freebind: bool) -> Result<()> {
+  set_int_sockopt(fd, libc::IPPROTO_IP, libc::IP_FREEBIND, freebind as i32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_freebind(_fd: i32, _freebind: bool) -> Result<()> {
+  Err(error("IP_FREEBIND is only supported on Linux".to_string()))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(fd: i32, device: &str) -> Result<()> {
+  resolve_libc_err(unsafe {
+    libc::setsockopt(
+      fd,
+      libc::SOL_SOCKET,
+      libc::SO_BINDTODEVICE,
+      device.as_ptr() as *const libc::c_void,
+      device.len() as u32,
+    )
+  })?;
+
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_fd: i32, _device: &str) -> Result<()> {
+  Err(error(
+    "SO_BINDTODEVICE is only supported on Linux".to_string(),
+  ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_defer_accept(fd: i32, seconds: i32) -> Result<()> {
+  set_int_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, seconds)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_defer_accept(_fd: i32, _seconds: i32) -> Result<()> {
+  Err(error(
+    "TCP_DEFER_ACCEPT is only supported on Linux".to_string(),
+  ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_incoming_cpu(fd: i32, cpu: i32) -> Result<()> {
+  set_int_sockopt(fd, libc::SOL_SOCKET, libc::SO_INCOMING_CPU, cpu)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_incoming_cpu(_fd: i32, _cpu: i32) -> Result<()> {
+  Err(error(
+    "SO_INCOMING_CPU is only supported on Linux".to_string(),
+  ))
+}
+
+#[cfg(target_os = "linux")]
+fn preset_program(steering: &str, group_size: Option<u32>) -> Result<Vec<(u32, u32, u32, u32)>> {
+  // `ret a` isn't defined in libc
+  const BPF_A: u32 = 0x10;
+
+  let ancillary = match steering {
+    "cpu" => libc::SKF_AD_CPU,
+    "hash" => libc::SKF_AD_RXHASH,
+    _ => {
+      return Err(error(format!(
+        "unexpected steering '{}', expect 'cpu' or 'hash'",
+        steering
+      )))
+    }
+  };
+
+  // the kernel falls back to its own hash if the index is out of the group,
+  // which makes the program do nothing silently
+  let group_size = match group_size {
+    Some(0) => return Err(error("'groupSize' should be greater than 0".to_string())),
+    Some(group_size) => group_size,
+    None => {
+      return Err(error(format!(
+        "'groupSize' is required by steering '{}'",
+        steering
+      )))
+    }
+  };
+
+  Ok(vec![
+    // ld #cpu or ld #rxhash
+    (
+      libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+      0,
+      0,
+      (libc::SKF_AD_OFF + ancillary) as u32,
+    ),
+    // mod #group_size
+    (
+      libc::BPF_ALU | libc::BPF_MOD | libc::BPF_K,
+      0,
+      0,
+      group_size,
+    ),
+    // ret a
+    (libc::BPF_RET | BPF_A, 0, 0, 0),
+  ])
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preset_program(_steering: &str, _group_size: Option<u32>) -> Result<Vec<(u32, u32, u32, u32)>> {
+  Err(error(
+    "SO_ATTACH_REUSEPORT_CBPF is only supported on Linux".to_string(),
+  ))
+}
+
+#[cfg(target_os = "linux")]
+fn attach_cbpf(fd: i32, program: &[(u32, u32, u32, u32)]) -> Result<()> {
+  use std::mem;
+
+  if program.len() > libc::BPF_MAXINSNS as usize {
+    return Err(error(format!(
+      "a program has at most {} instructions, got {}",
+      libc::BPF_MAXINSNS,
+      program.len()
+    )));
+  }
+
+  let mut filters = program
+    .iter()
+    .enumerate()
+    .map(|(index, &(code, jt, jf, k))| {
+      let out_of_range = |field: &str| {
+        error(format!(
+          "'{}' of instruction {} is out of range",
+          field, index
+        ))
+      };
+      Ok(libc::sock_filter {
+        code: u16::try_from(code).map_err(|_| out_of_range("code"))?,
+        jt: u8::try_from(jt).map_err(|_| out_of_range("jt"))?,
+        jf: u8::try_from(jf).map_err(|_| out_of_range("jf"))?,
+        k,
+      })
+    })
+    .collect::<Result<Vec<_>>>()?;
+  let prog = libc::sock_fprog {
+    len: filters.len() as u16,
+    filter: filters.as_mut_ptr(),
+  };
+
+  resolve_libc_err(unsafe {
+    libc::setsockopt(
+      fd,
+      libc::SOL_SOCKET,
+      libc::SO_ATTACH_REUSEPORT_CBPF,
+      &prog as *const _ as *const libc::c_void,
+      mem::size_of::<libc::sock_fprog>() as u32,
+    )
+  })?;
+
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn attach_cbpf(_fd: i32, _program: &[(u32, u32, u32, u32)]) -> Result<()> {
+  Err(error(
+    "SO_ATTACH_REUSEPORT_CBPF is only supported on Linux".to_string(),
+  ))
+}
@@ -1,14 +1,25 @@
+use std::cell::{Cell, RefCell};
 use std::collections::LinkedList;
 use std::mem;
 use std::os::raw::c_int;
-
-use crate::socket::{self, get_loop, sockaddr_from_string, Emitter, HandleData, UvRefence};
+use std::ptr;
+use std::time::Instant;
+
+use crate::blocking;
+use crate::queue;
+use crate::socket::{
+  self, bind_with_options, get_loop, sockaddr_from_string, BindOptions, BoundPath, Emitter,
+  FirstError, HandleData, PathLock, UvRefence,
+};
+use crate::sockopt;
+use crate::stats::{Counters, SocketStats};
+use crate::timestamp::{self, CmsgBuf};
 use crate::util::{
   addr_to_string, buf_into_vec, error, get_err, resolve_libc_err, resolve_uv_err, set_clo_exec,
-  set_non_block, socket_addr_to_string, uv_err_msg,
+  set_non_block, socket_addr_to_string, throw_errno_error, uv_err_msg, MsgBufs,
 };
-use crate::uv_handle::{insert_handle, remove_handle};
-use libc::{sockaddr, sockaddr_un, EAGAIN, EINTR, EINVAL, ENOBUFS, EWOULDBLOCK};
+use crate::uv_handle::{insert_handle, remove_handle, set_owner, Teardown};
+use libc::{sockaddr_un, EAGAIN, EINTR, EINVAL, ENOBUFS, EWOULDBLOCK};
 use napi::{Env, JsBuffer, JsFunction, JsNumber, JsObject, JsString, JsUnknown, Ref, Result};
 use nix::errno::errno;
 use uv_sys::sys;
@@ -37,28 +48,119 @@ enum State {
 }
 
 struct MsgInfoItem {
-  msg: Vec<u8>,
+  msg: MsgBufs,
   cb: Option<Ref<()>>,
+  enqueued_at: Instant,
 }
 
+/**
+ * js callbacks called by the wrap, e.g. listeners of "data", might call its
+ * methods again, so that methods only take `&self` and the state is kept in
+ * cells. `RefCell`s are never borrowed while calling js.
+ */
 #[napi]
 pub struct SeqpacketSocketWrap {
   fd: i32,
   env: Env,
-  handle: *mut sys::uv_poll_t,
-  msg_queue: LinkedList<MsgInfoItem>,
+  handle: Cell<*mut sys::uv_poll_t>,
+  msg_queue: RefCell<LinkedList<MsgInfoItem>>,
   /**
    * The length of bytes that we use to read buffers.
    */
-  read_buf_size: usize,
-  state: State,
-  poll_events: i32,
+  read_buf_size: Cell<usize>,
+  state: Cell<State>,
+  poll_events: Cell<i32>,
   emitter: Emitter,
+  /**
+   * The path passed to listen(). getsockname() returns the temporary path
+   * for sockets bound atomically so that we keep it here.
+   */
+  bound_path: RefCell<Option<String>>,
+  path_lock: RefCell<Option<PathLock>>,
+  unlink_path: RefCell<Option<BoundPath>>,
+  counters: RefCell<Counters>,
+  /**
+   * Whether the read side has been shut down with shutdown_read().
+   */
+  read_shutdown: Cell<bool>,
+  /**
+   * Whether the socket stays open for writing after the other side ends.
+   * Otherwise it shuts down the write side once messages are flushed.
+   */
+  allow_half_open: Cell<bool>,
+  /**
+   * Whether SO_TIMESTAMPNS is enabled, i.e. "_data" events come with the
+   * time messages are received.
+   */
+  recv_timestamp: Cell<bool>,
+  /**
+   * Whether messages are being flushed. Writes from the callbacks of written
+   * messages only enqueue messages so that the stack doesn't grow with them.
+   */
+  flushing: Cell<bool>,
+}
+
+enum Received {
+  /**
+   * A message, which might be empty, and the time it's received.
+   */
+  Msg(Vec<u8>, Option<libc::timespec>),
+  /**
+   * The other side has ended.
+   */
+  End,
+  WouldBlock,
+}
+
+/**
+ * recvmsg() returns 0 for both zero-length messages and the end of stream.
+ * With SO_TIMESTAMPNS, every message comes with SCM_TIMESTAMPNS but the end
+ * of stream comes with no control message so that we can tell them apart.
+ * Unlike SO_PASSCRED, it doesn't autobind the socket to an address.
+ */
+#[cfg(target_os = "linux")]
+fn enable_end_detection(fd: i32) -> Result<()> {
+  timestamp::set_recv_timestamp(fd, true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_end_detection(_fd: i32) -> Result<()> {
+  Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn is_end(msg: &libc::msghdr, size: usize) -> bool {
+  size == 0 && msg.msg_controllen == 0 && msg.msg_flags & libc::MSG_CTRUNC == 0
+}
+
+// zero-length messages can't be told apart from the end of stream
+#[cfg(not(target_os = "linux"))]
+fn is_end(_msg: &libc::msghdr, size: usize) -> bool {
+  size == 0
+}
+
+/**
+ * Write the buffers of a message with sendmsg() so that they are sent as
+ * one message.
+ */
+fn send_msg(fd: i32, bufs: &mut MsgBufs) -> isize {
+  let mut msg = unsafe { mem::MaybeUninit::<libc::msghdr>::zeroed().assume_init() };
+  let mut iovecs = bufs.iovecs();
+
+  msg.msg_iovlen = iovecs.len() as _;
+  msg.msg_iov = iovecs.as_mut_ptr();
+
+  loop {
+    let ret = unsafe { libc::sendmsg(fd, &msg as *const _, 0) };
+    if !(ret == -1 && errno() == EINTR) {
+      return ret;
+    }
+  }
 }
 
 impl UvRefence for SeqpacketSocketWrap {
   fn get_handle(&self) -> *mut sys::uv_poll_t {
-    self.handle
+    self.handle.get()
   }
 }
 
@@ -91,137 +193,244 @@ impl SeqpacketSocketWrap {
 
     set_non_block(fd)?;
     set_clo_exec(fd)?;
+    enable_end_detection(fd)?;
 
-    let emit_fn = ee.get_named_property::<JsFunction>("emit")?;
+    let emitter = Emitter::new(env, &ee, "SeqpacketSocket")?;
+    let uv_loop = get_loop(&env)?;
     let handle = Box::into_raw(Box::new(unsafe {
       let mut handle = mem::MaybeUninit::<sys::uv_poll_t>::zeroed().assume_init();
       handle.data = std::ptr::null_mut();
       handle
     }));
-    insert_handle(unsafe { mem::transmute(handle) })?;
-
-    let uv_loop = get_loop(&env)?;
-    resolve_uv_err(unsafe { sys::uv_poll_init(uv_loop, handle, fd) })?;
+    if let Err(e) = resolve_uv_err(unsafe { sys::uv_poll_init(uv_loop, handle, fd) }) {
+      // libuv doesn't know the handle yet
+      drop(unsafe { Box::from_raw(handle) });
+      return Err(e);
+    }
+    // only register handles that can be passed to uv_close() in the clean
+    // up hook
+    if let Err(e) = insert_handle(env, handle as *mut _) {
+      unsafe { sys::uv_close(handle as *mut _, Some(on_close)) };
+      return Err(e);
+    }
 
     Ok(Self {
       fd,
-      emitter: Emitter::new(env, emit_fn)?,
+      emitter,
       env,
-      handle,
-      msg_queue: LinkedList::new(),
-      read_buf_size: DEFAULT_READ_BUF_SIZE,
-      state: State::NewSocket,
-      poll_events: 0,
+      handle: Cell::new(handle),
+      msg_queue: RefCell::new(LinkedList::new()),
+      read_buf_size: Cell::new(DEFAULT_READ_BUF_SIZE),
+      state: Cell::new(State::NewSocket),
+      poll_events: Cell::new(0),
+      bound_path: RefCell::new(None),
+      path_lock: RefCell::new(None),
+      unlink_path: RefCell::new(None),
+      counters: RefCell::new(Counters::default()),
+      read_shutdown: Cell::new(false),
+      allow_half_open: Cell::new(true),
+      recv_timestamp: Cell::new(false),
+      flushing: Cell::new(false),
     })
   }
 
   #[napi]
-  pub fn init(&mut self, env: Env, this_obj: JsObject) -> Result<()> {
+  pub fn init(&self, env: Env, this_obj: JsObject) -> Result<()> {
+    let handle = self.handle.get();
     let handle_data = Box::into_raw(Box::new(HandleData::new(env, this_obj)?));
-    unsafe { (*self.handle).data = handle_data as *mut _ };
-    Ok(())
+    unsafe { (*handle).data = handle_data as *mut _ };
+    set_owner(env, handle as *mut _, self as *const Self)?;
+    self.update_refs()
   }
 
   #[napi]
   pub fn state(&self) -> i32 {
-    self.state as i32
+    self.state.get() as i32
   }
 
   #[napi]
-  pub fn close(&mut self) -> Result<()> {
-    if self.state == State::Closed {
+  pub fn close(&self) -> Result<()> {
+    if self.is_closed() {
       return Ok(());
     }
 
-    let env = self.env;
+    let cbs = self.release()?;
+    // "close" is emitted even if callbacks throw, and the first exception is
+    // thrown after it
+    let mut first_error = socket::cancel_write_cbs(self.env, &self.emitter, cbs);
+    first_error.check(self.emitter.emit_event("close"));
+    first_error.check(self.emitter.unref());
+
+    first_error.into_result()
+  }
+
+  /**
+   * Stop polling, drop messages waiting to be sent and close the fd.
+   * Returns callbacks of the dropped messages.
+   */
+  fn release(&self) -> Result<Vec<Ref<()>>> {
     // close handle
     self.stop_poll()?;
-
-    unsafe {
-      sys::uv_close(self.handle as *mut _, Some(on_close));
-      remove_handle(mem::transmute(self.handle))?;
-    };
+    self.close_handle()?;
 
     // release msg_queue
-    loop {
-      let msg = self.msg_queue.pop_front();
-      if msg.is_none() {
-        break;
-      }
-
-      let mut msg = msg.unwrap();
-      if msg.cb.is_some() {
-        let mut cb = msg.cb.take().unwrap();
-        cb.unref(env)?;
+    let msgs = mem::take(&mut *self.msg_queue.borrow_mut());
+    let mut cbs = vec![];
+    for mut msg in msgs {
+      self.counters.borrow_mut().dropped(msg.msg.len());
+      if let Some(cb) = msg.cb.take() {
+        cbs.push(cb);
       }
     }
 
     // release js objects
-    socket::close(self.fd)?;
-    self.state = State::Closed;
-    self.emitter.emit_event("close")?;
+    self.state.set(State::Closed);
+    // release the lock only after the fd is closed so that the next owner
+    // of the path never races with this socket
+    let ret = socket::close(self.fd);
+    self.path_lock.take();
+    ret?;
+
+    Ok(cbs)
+  }
+
+  /**
+   * Stop polling and release the uv handle without closing the fd. Returns
+   * the fd so that it can be adopted by another wrap, e.g. in a worker.
+   *
+   * Queued messages aren't handed over so that it refuses to detach until
+   * they are flushed. Listening sockets aren't detached by js, but if they
+   * were, `unlink_on_teardown` isn't handed over and the path is never
+   * unlinked after it.
+   */
+  #[napi]
+  pub fn detach(&self) -> Result<i32> {
+    if self.is_closed() {
+      return Err(error("socket has been closed".to_string()));
+    }
+    if !self.msg_queue.borrow().is_empty() {
+      return Err(error(
+        "can't detach a socket with pending messages".to_string(),
+      ));
+    }
+    if self.path_lock.borrow().is_some() {
+      return Err(error(
+        "can't detach a socket holding a path lock".to_string(),
+      ));
+    }
+
+    self.stop_poll()?;
+    self.close_handle()?;
+    self.unlink_path.take();
+
+    self.state.set(State::Closed);
     self.emitter.unref()?;
 
-    Ok(())
+    Ok(self.fd)
+  }
+
+  /**
+   * Pass the handle to uv_close(). It's freed in on_close() and the wrap
+   * never touches it again.
+   */
+  fn close_handle(&self) -> Result<()> {
+    let handle = self.handle.replace(ptr::null_mut());
+    // the clean up hook of the environment might have closed it
+    if unsafe { sys::uv_is_closing(handle as *mut _) } == 0 {
+      unsafe { sys::uv_close(handle as *mut _, Some(on_close)) };
+    }
+    remove_handle(self.env, handle as *mut _)
+  }
+
+  fn update_refs(&self) -> Result<()> {
+    socket::update_refs(self.handle.get(), &self.emitter)
+  }
+
+  fn is_closed(&self) -> bool {
+    self.state.get() == State::Closed
+  }
+
+  fn check_handle(&self) -> Result<*mut sys::uv_poll_t> {
+    let handle = self.handle.get();
+    if handle.is_null() {
+      return Err(error("socket has been closed".to_string()));
+    }
+
+    Ok(handle)
+  }
+
+  fn add_poll_events(&self, events: sys::uv_poll_event) -> Result<()> {
+    self.poll_events.set(self.poll_events.get() | events as i32);
+    self.reset_poll()
+  }
+
+  fn remove_poll_events(&self, events: sys::uv_poll_event) -> Result<()> {
+    self
+      .poll_events
+      .set(self.poll_events.get() & !(events as i32));
+    self.reset_poll()
   }
 
   #[napi]
-  pub fn shutdown_write(&mut self) -> Result<()> {
-    resolve_libc_err(unsafe { libc::shutdown(self.fd, libc::SHUT_WR) })?;
-    self.state = State::ShutDown;
+  pub fn shutdown_write(&self) -> Result<()> {
+    // i.e. SHUT_WR if the read side is still open
+    let how = if self.read_shutdown.get() {
+      libc::SHUT_RDWR
+    } else {
+      libc::SHUT_WR
+    };
+    resolve_libc_err(unsafe { libc::shutdown(self.fd, how) })?;
+    self.state.set(State::ShutDown);
     self.emitter.emit_event("_shutdown")?;
     Ok(())
   }
 
+  /**
+   * Shut down the read side with SHUT_RD and stop reading. Messages that
+   * haven't been read are dropped and the other side fails to write.
+   */
   #[napi]
-  pub fn uv_refer(&mut self) {
-    self.refer();
+  pub fn shutdown_read(&self) -> Result<()> {
+    self.check_handle()?;
+    if self.read_shutdown.get() {
+      return Ok(());
+    }
+
+    resolve_libc_err(unsafe { libc::shutdown(self.fd, libc::SHUT_RD) })?;
+    self.read_shutdown.set(true);
+    self.remove_poll_events(sys::uv_poll_event::UV_READABLE)
   }
 
   #[napi]
-  pub fn uv_unrefer(&mut self) {
-    self.unref();
+  pub fn set_allow_half_open(&self, allow_half_open: bool) {
+    self.allow_half_open.set(allow_half_open);
   }
 
-  fn emit_error(&mut self, error: napi::Error) {
-    let env = self.env;
-
-    // TODO unwrap
-    env
-      .run_in_scope(|| {
-        let event = env.create_string("_error").unwrap();
-        let error = self.env.create_error(error).unwrap();
-        self
-          .emitter
-          .emit(&[event.into_unknown(), error.into_unknown()])
-          .unwrap();
-        Ok(())
-      })
-      .unwrap();
+  #[napi]
+  pub fn uv_refer(&self) {
+    self.refer();
   }
 
-  fn bind(&self, bindpath: &str) -> Result<()> {
-    unsafe {
-      let (sockaddr, _) = sockaddr_from_string(bindpath)?;
-      resolve_libc_err(libc::bind(
-        self.fd,
-        &sockaddr as *const _ as *const sockaddr,
-        mem::size_of::<sockaddr_un>() as u32,
-      ))?;
-    };
+  #[napi]
+  pub fn uv_unrefer(&self) {
+    self.unref();
+  }
 
-    Ok(())
+  fn emit_error(&self, error: napi::Error) {
+    socket::emit_error(self.env, &self.emitter, error);
   }
 
-  fn handle_connect(&mut self, status: i32, _events: i32) {
+  fn handle_connect(&self, status: i32, _events: i32) {
     if !self.check_uv_status(status, "handle_connect") {
       return;
     }
 
-    self.emitter.emit_event("_connect").unwrap();
+    if let Err(e) = self.emitter.emit_event("_connect") {
+      self.emit_error(e);
+    }
   }
 
-  fn handle_socket(&mut self, status: i32, _events: i32) {
+  fn handle_socket(&self, status: i32, _events: i32) {
     if !self.check_uv_status(status, "handle_socket") {
       return;
     }
@@ -244,7 +453,7 @@ impl SeqpacketSocketWrap {
     let env = self.env;
     let addr = addr_to_string(&addr);
 
-    match env.run_in_scope(|| {
+    if let Err(e) = env.run_in_scope(|| {
       let mut args: Vec<JsUnknown> = vec![];
       let js_event = env.create_string("_connection")?;
       args.push(js_event.into_unknown());
@@ -255,20 +464,21 @@ impl SeqpacketSocketWrap {
       self.emitter.emit(&args)?;
       Ok(())
     }) {
-      Ok(_) => {}
-      Err(e) => {
-        let _ = env.throw_error(&e.reason, None);
-      }
+      self.emit_error(e);
     }
   }
 
-  fn handle_io(&mut self, status: i32, events: i32) {
+  fn handle_io(&self, status: i32, events: i32) {
     if !self.check_uv_status(status, "handle_io") {
       return;
     }
 
     if events & sys::uv_poll_event::UV_WRITABLE as i32 != 0 {
-      self.flush();
+      self.flush(None);
+    }
+
+    if self.is_closed() {
+      return;
     }
 
     if events & sys::uv_poll_event::UV_READABLE as i32 != 0 {
@@ -281,31 +491,37 @@ impl SeqpacketSocketWrap {
     }
   }
 
-  fn finish_msg(&self, mut msg: MsgInfoItem) -> Result<()> {
+  /**
+   * Exceptions thrown by the callback go to `cb_errors` if there is a js
+   * caller on the stack, e.g. write() writing the message right away, or to
+   * `uncaughtException` otherwise.
+   */
+  fn finish_msg(
+    &self,
+    mut msg: MsgInfoItem,
+    errno: Option<i32>,
+    cb_errors: Option<&mut FirstError>,
+  ) {
     let env = self.env;
 
-    if msg.cb.is_none() {
-      return Ok(());
-    }
-
-    let mut cb = msg.cb.take().unwrap();
-
-    let _ = env.run_in_scope(|| {
-      let args: Vec<JsUnknown> = vec![];
-      let cb: JsFunction = env.get_reference_value(&cb)?;
-      let _ = cb.call(None, &args).map_err(|e| {
-        let _ = self.env.throw_error(&e.reason, None);
-      });
-
-      Ok(())
-    });
+    let cb = match msg.cb.take() {
+      Some(cb) => cb,
+      None => return,
+    };
 
-    cb.unref(env)?;
-    Ok(())
+    let ret = env.run_in_scope(|| socket::call_write_cb(env, &self.emitter, cb, errno));
+    match cb_errors {
+      Some(cb_errors) => cb_errors.check(ret),
+      None => {
+        if let Err(e) = ret {
+          self.emit_error(e);
+        }
+      }
+    }
   }
 
-  fn flush(&mut self) {
-    match self._flush() {
+  fn flush(&self, cb_errors: Option<&mut FirstError>) {
+    match self._flush(cb_errors) {
       Ok(_) => {}
       Err(e) => {
         self.emit_error(e);
@@ -313,154 +529,232 @@ impl SeqpacketSocketWrap {
     }
   }
 
-  fn _flush(&mut self) -> Result<()> {
-    let mut finished_msgs: LinkedList<MsgInfoItem> = LinkedList::new();
+  fn _flush(&self, cb_errors: Option<&mut FirstError>) -> Result<()> {
+    if self.flushing.get() {
+      return Ok(());
+    }
+
+    self.flushing.set(true);
+    let ret = self.flush_queue(cb_errors);
+    self.flushing.set(false);
 
+    ret
+  }
+
+  fn flush_queue(&self, mut cb_errors: Option<&mut FirstError>) -> Result<()> {
     loop {
-      let msg = self.msg_queue.pop_front();
-      if msg.is_none() {
+      let mut finished_msgs: LinkedList<(MsgInfoItem, Option<i32>)> = LinkedList::new();
+      let ret = self.write_msgs(&mut finished_msgs);
+
+      // callbacks might close the socket or write more messages
+      while let Some((msg, errno)) = finished_msgs.pop_front() {
+        self.finish_msg(msg, errno, cb_errors.as_deref_mut());
+      }
+
+      if self.is_closed() {
+        return Ok(());
+      }
+
+      let blocked = ret?;
+
+      if blocked || self.msg_queue.borrow().is_empty() {
         break;
       }
+    }
 
-      let mut msg = msg.unwrap();
-      let size = msg.msg.len();
-      let msg_ptr = msg.msg.as_ptr();
+    if !self.msg_queue.borrow().is_empty() {
+      self.add_poll_events(sys::uv_poll_event::UV_WRITABLE)?;
+    } else if self.state.get() == State::ShuttingDown {
+      self.shutdown_write()?;
+    } else if self.poll_events.get() & sys::uv_poll_event::UV_WRITABLE as i32 != 0 {
+      self.remove_poll_events(sys::uv_poll_event::UV_WRITABLE)?;
+    }
 
-      let mut ret: i32;
-      loop {
-        ret = unsafe { libc::write(self.fd, msg_ptr as *const _, size) } as i32;
+    Ok(())
+  }
 
-        if !(ret == -1 && errno() == libc::EINTR) {
-          break;
-        }
+  /**
+   * Write messages until the queue is empty or the socket is not writable.
+   * Returns whether the socket is not writable.
+   */
+  fn write_msgs(&self, finished_msgs: &mut LinkedList<(MsgInfoItem, Option<i32>)>) -> Result<bool> {
+    loop {
+      let msg = self.msg_queue.borrow_mut().pop_front();
+      if msg.is_none() {
+        return Ok(false);
       }
 
+      let mut msg = msg.unwrap();
+      let size = msg.msg.len();
+      let ret = send_msg(self.fd, &mut msg.msg) as i32;
+
       if ret >= 0 {
+        self.counters.borrow_mut().written(ret as usize);
         if ret == (size as i32) {
-          finished_msgs.push_front(msg);
+          self.counters.borrow_mut().sent(msg.enqueued_at);
+          finished_msgs.push_back((msg, None));
         } else {
-          msg.msg = msg.msg[(ret as usize)..].to_owned();
-          self.msg_queue.push_front(msg);
-          break;
+          // the rest can't be sent as another message without breaking the
+          // boundary, which doesn't happen with the size checked in write()
+          self.counters.borrow_mut().dropped(size - ret as usize);
+          finished_msgs.push_back((msg, Some(libc::EMSGSIZE)));
+          return Err(error("message was written partially".to_string()));
         }
       } else {
-        self.msg_queue.push_front(msg);
-
         let err: i32 = errno();
         if err == EAGAIN || err == EWOULDBLOCK || err == ENOBUFS {
-          break;
-        } else {
-          resolve_libc_err(ret)?;
+          self.msg_queue.borrow_mut().push_front(msg);
+          self.counters.borrow_mut().retry(err);
+          return Ok(true);
         }
-      }
-    }
 
-    if self.msg_queue.len() > 0 {
-      self.poll_events |= sys::uv_poll_event::UV_WRITABLE as i32;
-      self.reset_poll()?;
-    } else {
-      if self.state == State::ShuttingDown {
-        self.shutdown_write()?;
-      } else {
-        self.poll_events ^= sys::uv_poll_event::UV_WRITABLE as i32;
-        self.reset_poll()?;
+        // the message is dropped and its callback gets the error
+        let e = get_err();
+        self.counters.borrow_mut().dropped(size);
+        finished_msgs.push_back((msg, Some(err)));
+        return Err(e);
       }
     }
+  }
+
+  /**
+   * Read a message with recvmsg().
+   */
+  fn recv_msg(&self) -> Result<Received> {
+    let buf_len = self.read_buf_size.get();
+    let mut buf: Vec<u8> = vec![0; buf_len];
+    let mut iov = libc::iovec {
+      iov_base: buf.as_mut_ptr() as *mut _,
+      iov_len: buf_len,
+    };
+    let mut msg = unsafe { mem::MaybeUninit::<libc::msghdr>::zeroed().assume_init() };
+    msg.msg_iov = &mut iov as *mut _;
+    msg.msg_iovlen = 1;
+    let mut cmsg_buf = CmsgBuf::new();
+    cmsg_buf.attach(&mut msg);
 
+    let mut ret: i32;
     loop {
-      let msg = finished_msgs.pop_front();
-      if msg.is_none() {
+      ret = unsafe { libc::recvmsg(self.fd, &mut msg as *mut _, 0) } as i32;
+
+      if !(ret < 0 && errno() == EINTR) {
         break;
       }
-      let msg = msg.unwrap();
-      self.finish_msg(msg)?;
     }
 
-    Ok(())
+    if ret < 0 {
+      let err = errno();
+      if err == EAGAIN || err == EWOULDBLOCK {
+        return Ok(Received::WouldBlock);
+      }
+      return Err(get_err());
+    }
+
+    let size = ret as usize;
+    if is_end(&msg, size) {
+      return Ok(Received::End);
+    }
+
+    self
+      .counters
+      .borrow_mut()
+      .received(size, msg.msg_flags & libc::MSG_TRUNC != 0);
+    let ts = if self.recv_timestamp.get() {
+      timestamp::parse_recv_timestamp(&msg)
+    } else {
+      None
+    };
+
+    Ok(Received::Msg(buf[0..size].to_vec(), ts))
   }
 
-  fn _handle_readable(&mut self) -> Result<()> {
+  fn _handle_readable(&self) -> Result<()> {
     loop {
-      let buf_len = self.read_buf_size;
-      let mut buf: Vec<u8> = vec![0; buf_len];
-      let buf_ptr = buf.as_mut_ptr();
-      let mut ret: i32;
-      loop {
-        ret = unsafe { libc::read(self.fd, buf_ptr as *mut _, buf_len) } as i32;
-
-        if !(ret < 0 && errno() == EINTR) {
-          break;
-        }
+      let received = self.recv_msg()?;
+      if let Received::WouldBlock = received {
+        self.add_poll_events(sys::uv_poll_event::UV_READABLE)?;
+        break;
       }
 
-      if ret < 0 {
-        let err = errno();
-        if err == EAGAIN || err == EWOULDBLOCK {
-          self.poll_events |= sys::uv_poll_event::UV_READABLE as i32;
-          self.reset_poll()?;
-          break;
+      let is_end = matches!(received, Received::End);
+      let env = self.env;
+      let emitted = env.run_in_scope(|| {
+        let mut args: Vec<JsUnknown> = vec![];
+
+        if let Received::Msg(buf, ts) = received {
+          let js_event = env.create_string("_data")?;
+          args.push(js_event.into_unknown());
+          let js_buf = env.create_buffer_with_data(buf)?;
+          args.push(js_buf.into_unknown());
+          if self.recv_timestamp.get() {
+            args.push(timestamp::timestamp_to_js(env, ts)?);
+          }
         } else {
-          resolve_uv_err(ret)?;
-          break;
+          let js_event = env.create_string("end")?;
+          args.push(js_event.into_unknown());
         }
-      } else {
-        let size = ret as usize;
-        let env = self.env;
-        env.run_in_scope(|| {
-          let mut args: Vec<JsUnknown> = vec![];
-
-          if size == 0 {
-            let js_event = env.create_string("end")?;
-            args.push(js_event.into_unknown());
-          } else {
-            let js_event = env.create_string("_data")?;
-            args.push(js_event.into_unknown());
-            let js_buf = env.create_buffer_with_data(buf[0..size].to_vec())?;
-            args.push(js_buf.into_unknown());
-          }
-          self.emitter.emit(&args)?;
-          Ok(())
-        })?;
-
-        // stop recv if the buf size is zero
-        if size == 0 {
-          self.poll_events ^= sys::uv_poll_event::UV_READABLE as i32;
-          self.reset_poll()?;
-          break;
+        self.emitter.emit(&args)?;
+        Ok(())
+      });
+      // an exception thrown by listeners doesn't stop reading, nor skip the
+      // end of reading below
+      if let Err(e) = emitted {
+        self.emit_error(e);
+      }
+
+      if self.is_closed() {
+        break;
+      }
+
+      // stop recv once the other side has ended
+      if is_end {
+        self.remove_poll_events(sys::uv_poll_event::UV_READABLE)?;
+        // messages written in "end" listeners are still sent
+        if !self.allow_half_open.get() {
+          self.shutdown_when_flushed()?;
         }
+        break;
+      }
+
+      // listeners might pause the socket
+      if self.poll_events.get() & sys::uv_poll_event::UV_READABLE as i32 == 0 {
+        break;
       }
     }
 
     Ok(())
   }
 
-  fn reset_poll(&mut self) -> Result<()> {
-    let events = self.poll_events;
-    let is_closing = unsafe { sys::uv_is_closing(self.handle as *mut _) } != 0;
+  fn reset_poll(&self) -> Result<()> {
+    let events = self.poll_events.get();
+    let handle = self.handle.get();
+    if handle.is_null() {
+      return Ok(());
+    }
+    let is_closing = unsafe { sys::uv_is_closing(handle as *mut _) } != 0;
 
     if is_closing {
       return Ok(());
     }
 
-    // stop poll
     if events == 0 {
-      resolve_uv_err(unsafe { sys::uv_poll_stop(self.handle) })?;
-      return Ok(());
+      // stop poll
+      resolve_uv_err(unsafe { sys::uv_poll_stop(handle) })?;
+    } else {
+      resolve_uv_err(unsafe { sys::uv_poll_start(handle, events, Some(on_io)) })?;
     }
 
-    resolve_uv_err(unsafe { sys::uv_poll_start(self.handle, events, Some(on_io)) })?;
-
-    Ok(())
+    self.update_refs()
   }
 
-  fn stop_poll(&mut self) -> Result<()> {
-    self.poll_events = 0;
+  fn stop_poll(&self) -> Result<()> {
+    self.poll_events.set(0);
 
     self.reset_poll()?;
     Ok(())
   }
 
-  fn check_uv_status(&mut self, status: i32, op: &str) -> bool {
+  fn check_uv_status(&self, status: i32, op: &str) -> bool {
     if status < 0 {
       let msg = uv_err_msg(status);
       let err = error(format!("uv callback of {} failed with error: {}", op, &msg));
@@ -468,58 +762,170 @@ impl SeqpacketSocketWrap {
       return false;
     }
 
-    return true;
+    true
   }
 
   #[napi]
-  pub fn set_read_buf_size(&mut self, size: JsNumber) -> Result<()> {
+  pub fn set_read_buf_size(&self, size: JsNumber) -> Result<()> {
     let size = size.get_uint32()?;
-    self.read_buf_size = size as usize;
+    self.read_buf_size.set(size as usize);
     Ok(())
   }
 
   #[napi]
   pub fn get_read_buf_size(&self, env: Env) -> Result<JsNumber> {
-    env.create_uint32(self.read_buf_size as u32)
+    env.create_uint32(self.read_buf_size.get() as u32)
   }
 
   #[napi]
-  pub fn start_recv(&mut self) -> Result<()> {
-    self.poll_events |= sys::uv_poll_event::UV_READABLE as i32;
-    self.reset_poll()?;
+  pub fn set_recv_timestamp(&self, enabled: bool) -> Result<()> {
+    // SO_TIMESTAMPNS stays enabled on Linux for enable_end_detection()
+    timestamp::set_recv_timestamp(self.fd, enabled || cfg!(target_os = "linux"))?;
+    self.recv_timestamp.set(enabled);
     Ok(())
   }
 
+  #[napi]
+  pub fn get_recv_timestamp(&self) -> bool {
+    self.recv_timestamp.get()
+  }
+
+  #[napi]
+  pub fn stats(&self) -> SocketStats {
+    self.counters.borrow().snapshot()
+  }
+
+  #[napi]
+  pub fn get_unread_bytes(&self) -> Result<u32> {
+    queue::unread_bytes(self.fd)
+  }
+
+  #[napi]
+  pub fn get_unsent_bytes(&self) -> Result<u32> {
+    queue::unsent_bytes(self.fd)
+  }
+
+  #[napi]
+  pub fn get_max_msg_size(&self) -> Result<u32> {
+    queue::max_msg_size(self.fd)
+  }
+
+  /**
+   * Messages are never split so that the ones that can't fit in the send
+   * buffer are rejected before they get queued.
+   */
+  fn check_msg_size(&self, env: Env, size: usize) -> Result<()> {
+    let max = queue::max_msg_size(self.fd)? as usize;
+    if size > max {
+      let msg = format!(
+        "EMSGSIZE: the message of {} bytes is larger than the max message size {}",
+        size, max
+      );
+      return Err(throw_errno_error(env, libc::EMSGSIZE, &msg));
+    }
+
+    Ok(())
+  }
+
+  #[napi]
+  pub fn peek(&self, env: Env, size: Option<u32>) -> Result<Option<JsBuffer>> {
+    match queue::peek(self.fd, size)? {
+      Some(data) => Ok(Some(env.create_buffer_with_data(data)?.into_raw())),
+      None => Ok(None),
+    }
+  }
+
+  #[napi]
+  pub fn get_sock_opt(&self, env: Env, name: String) -> Result<JsUnknown> {
+    sockopt::get_sock_opt(env, self.fd, &name)
+  }
+
+  #[napi]
+  pub fn set_sock_opt(&self, env: Env, name: String, value: JsUnknown) -> Result<()> {
+    sockopt::set_sock_opt(env, self.fd, &name, value)
+  }
+
+  #[napi]
+  pub fn get_raw_sock_opt(
+    &self,
+    env: Env,
+    level: i32,
+    optname: i32,
+    length: u32,
+  ) -> Result<JsBuffer> {
+    sockopt::get_raw_sock_opt(env, self.fd, level, optname, length)
+  }
+
+  #[napi]
+  pub fn set_raw_sock_opt(
+    &self,
+    env: Env,
+    level: i32,
+    optname: i32,
+    value: JsBuffer,
+  ) -> Result<()> {
+    sockopt::set_raw_sock_opt(env, self.fd, level, optname, value)
+  }
+
+  #[napi]
+  pub fn start_recv(&self) -> Result<()> {
+    if self.read_shutdown.get() {
+      return Ok(());
+    }
+
+    self.add_poll_events(sys::uv_poll_event::UV_READABLE)
+  }
+
+  #[napi]
+  pub fn stop_recv(&self) -> Result<()> {
+    self.remove_poll_events(sys::uv_poll_event::UV_READABLE)
+  }
+
   #[napi]
   pub fn address(&self, env: Env) -> Result<JsString> {
     let str = socket_addr_to_string(self.fd)?;
-    env.create_string(&str)
+    match self.bound_path.borrow().as_ref() {
+      Some(bound_path) => env.create_string(bound_path),
+      None => env.create_string(&str),
+    }
   }
 
   #[napi]
-  pub fn listen(&self, bindpath: JsString, backlog: JsNumber) -> Result<()> {
+  pub fn listen(
+    &self,
+    bindpath: String,
+    backlog: JsNumber,
+    options: Option<BindOptions>,
+  ) -> Result<()> {
     // Should never call listen() with a fd for multiple times.
-    let bindpath = bindpath.into_utf8()?;
+    // String keeps NULs of abstract socket addresses, unlike JsString::as_str()
+    let bindpath = bindpath.as_str();
     let backlog = backlog.get_int32()?;
+    let options = options.unwrap_or_default();
+    let fd = self.fd;
+    let listen = || resolve_libc_err(unsafe { libc::listen(fd, backlog) }).map(|_| ());
 
-    self.bind(bindpath.as_str()?)?;
-    resolve_libc_err(unsafe { libc::listen(self.fd, backlog) })?;
+    *self.path_lock.borrow_mut() = bind_with_options(fd, bindpath, &options, listen)?;
+    *self.unlink_path.borrow_mut() = BoundPath::from_options(bindpath, &options);
+    *self.bound_path.borrow_mut() = Some(bindpath.to_string());
 
     // poll UV_DISCONNECT?
     // start poll
+    let handle = self.check_handle()?;
     resolve_uv_err(unsafe {
       sys::uv_poll_start(
-        self.handle,
+        handle,
         sys::uv_poll_event::UV_READABLE as i32,
         Some(on_socket),
       )
     })?;
 
-    Ok(())
+    self.update_refs()
   }
 
   #[napi]
-  pub fn connect(&mut self, server_path: JsString) -> Result<()> {
+  pub fn connect(&self, server_path: JsString) -> Result<()> {
+    let handle = self.check_handle()?;
     let server_path = server_path.into_utf8()?;
     let path = server_path.as_str()?;
     let (mut sockaddr, addr_len) = sockaddr_from_string(path)?;
@@ -553,66 +959,232 @@ impl SeqpacketSocketWrap {
 
     unsafe {
       sys::uv_poll_start(
-        self.handle,
+        handle,
         sys::uv_poll_event::UV_WRITABLE as i32,
         Some(on_connect),
       )
     };
 
-    Ok(())
+    self.update_refs()
   }
 
   #[napi]
   pub fn write(
-    &mut self,
+    &self,
     env: Env,
     buf: JsBuffer,
     offset: JsNumber,
     length: JsNumber,
     cb: Option<JsFunction>,
   ) -> Result<()> {
-    if self.state >= State::ShuttingDown {
+    if self.state.get() >= State::ShuttingDown {
       return Err(error("socket has been shutdown".to_string()));
     }
     let offset = offset.get_int32()?;
     let length = length.get_int32()?;
-    let msg = buf_into_vec(buf, offset, length)?;
-    self.msg_queue.push_back(MsgInfoItem {
+    let msg = buf_into_vec(env, buf, offset, length)?;
+    self.enqueue(env, msg.into(), cb)
+  }
+
+  /**
+   * Write the buffers together as one message without concatenating them.
+   */
+  #[napi]
+  pub fn writev(&self, env: Env, bufs: Vec<JsBuffer>, cb: Option<JsFunction>) -> Result<()> {
+    if self.state.get() >= State::ShuttingDown {
+      return Err(error("socket has been shutdown".to_string()));
+    }
+    let msg = MsgBufs::from_js(env, bufs)?;
+    self.enqueue(env, msg, cb)
+  }
+
+  fn enqueue(&self, env: Env, msg: MsgBufs, cb: Option<JsFunction>) -> Result<()> {
+    self.check_msg_size(env, msg.len())?;
+    let cb = match cb {
+      Some(cb) => Some(env.create_reference(cb)?),
+      None => None,
+    };
+    self.counters.borrow_mut().enqueue(msg.len());
+    self.msg_queue.borrow_mut().push_back(MsgInfoItem {
       msg,
-      cb: match cb {
-        Some(cb) => {
-          let cb_ref = env.create_reference(cb)?;
-          Some(cb_ref)
-        }
-        None => None,
-      },
+      cb,
+      enqueued_at: Instant::now(),
     });
 
-    self.flush();
-
-    Ok(())
+    // callbacks of messages written right away throw to the caller
+    let mut cb_errors = FirstError::new(env);
+    self.flush(Some(&mut cb_errors));
+    cb_errors.into_result()
   }
 
   #[napi]
-  pub fn shutdown_when_flushed(&mut self) -> Result<()> {
-    self.state = State::ShuttingDown;
+  pub fn shutdown_when_flushed(&self) -> Result<()> {
+    if self.state.get() >= State::ShuttingDown {
+      return Ok(());
+    }
+    self.state.set(State::ShuttingDown);
 
-    if self.msg_queue.len() == 0 {
+    if self.msg_queue.borrow().is_empty() {
       self.shutdown_write()?;
     }
     // else shutdown when msgs flushed
     Ok(())
   }
+
+  /**
+   * Write messages in the queue with poll() instead of the event loop, e.g.
+   * in "exit" listeners. Their callbacks are called before it returns and
+   * the first exception they throw is thrown.
+   */
+  #[napi]
+  pub fn flush_sync(&self, timeout: Option<u32>) -> Result<()> {
+    self.check_handle()?;
+    self.flush_until(blocking::deadline(timeout), "flushSync")
+  }
+
+  fn flush_until(&self, deadline: Option<Instant>, op: &str) -> Result<()> {
+    if self.flushing.get() {
+      return Err(error(format!("can't call {} in callbacks of write()", op)));
+    }
+
+    let mut cb_errors = FirstError::new(self.env);
+    let ret = (|| loop {
+      self._flush(Some(&mut cb_errors))?;
+      if self.is_closed() || self.msg_queue.borrow().is_empty() {
+        return Ok(());
+      }
+      if !blocking::wait_fd(self.fd, libc::POLLOUT, deadline)? {
+        return Err(blocking::timed_out(op));
+      }
+    })();
+    // exceptions of callbacks are thrown before errors of writing
+    cb_errors.check(ret);
+    cb_errors.into_result()
+  }
+
+  /**
+   * Write a message with poll() instead of the event loop after the queue is
+   * flushed. The message is dropped if it times out.
+   */
+  #[napi]
+  pub fn write_sync(
+    &self,
+    env: Env,
+    buf: JsBuffer,
+    offset: JsNumber,
+    length: JsNumber,
+    timeout: Option<u32>,
+  ) -> Result<()> {
+    self.check_handle()?;
+    if self.state.get() >= State::ShuttingDown {
+      return Err(error("socket has been shutdown".to_string()));
+    }
+    let offset = offset.get_int32()?;
+    let length = length.get_int32()?;
+    let mut msg: MsgBufs = buf_into_vec(env, buf, offset, length)?.into();
+    self.check_msg_size(env, msg.len())?;
+    let deadline = blocking::deadline(timeout);
+    self.flush_until(deadline, "writeSync")?;
+    if self.is_closed() {
+      return Err(error("socket has been closed".to_string()));
+    }
+
+    let size = msg.len();
+    let enqueued_at = Instant::now();
+    self.counters.borrow_mut().enqueue(size);
+    loop {
+      let ret = send_msg(self.fd, &mut msg);
+
+      if ret >= 0 {
+        self.counters.borrow_mut().written(ret as usize);
+        if ret as usize == size {
+          self.counters.borrow_mut().sent(enqueued_at);
+          return Ok(());
+        }
+        self.counters.borrow_mut().dropped(size - ret as usize);
+        return Err(error("message was written partially".to_string()));
+      }
+
+      let err = errno();
+      if err == EAGAIN || err == EWOULDBLOCK || err == ENOBUFS {
+        self.counters.borrow_mut().retry(err);
+        if blocking::wait_fd(self.fd, libc::POLLOUT, deadline)? {
+          continue;
+        }
+        self.counters.borrow_mut().dropped(size);
+        return Err(blocking::timed_out("writeSync"));
+      }
+
+      let e = get_err();
+      self.counters.borrow_mut().dropped(size);
+      return Err(e);
+    }
+  }
+
+  /**
+   * Read a message with poll() instead of the event loop. Returns `None` if
+   * the other side has ended and throws if it times out.
+   */
+  #[napi]
+  pub fn recv_sync(&self, env: Env, timeout: Option<u32>) -> Result<Option<JsBuffer>> {
+    self.check_handle()?;
+    let deadline = blocking::deadline(timeout);
+    loop {
+      match self.recv_msg()? {
+        Received::Msg(buf, _) => return Ok(Some(env.create_buffer_with_data(buf)?.into_raw())),
+        Received::End => return Ok(None),
+        Received::WouldBlock => {}
+      }
+      if !blocking::wait_fd(self.fd, libc::POLLIN, deadline)? {
+        return Err(blocking::timed_out("recvSync"));
+      }
+    }
+  }
+}
+
+impl Teardown for SeqpacketSocketWrap {
+  fn teardown(&self) {
+    if self.is_closed() {
+      return;
+    }
+
+    socket::release_handle_data(self.handle.get());
+    if let Ok(cbs) = self.release() {
+      socket::drop_write_cbs(self.env, cbs);
+    }
+    if let Some(path) = self.unlink_path.take() {
+      path.unlink();
+    }
+    let _ = self.emitter.unref();
+  }
+}
+
+/**
+ * Runs when the js object gets garbage-collected. Sockets are only
+ * collectable while they are not polling, see `socket::update_refs()`.
+ */
+impl Drop for SeqpacketSocketWrap {
+  fn drop(&mut self) {
+    if self.is_closed() {
+      return;
+    }
+
+    socket::warn_unclosed(self.env, "SeqpacketSocket", self.fd);
+    if let Ok(cbs) = self.release() {
+      socket::drop_write_cbs(self.env, cbs);
+    }
+  }
 }
 
 extern "C" fn on_close(handle: *mut sys::uv_handle_t) {
   unsafe {
-    assert!(!(*handle).data.is_null(), "unexpected null handle data");
-  };
-  unsafe {
-    let mut data = Box::from_raw((*handle).data as *mut HandleData);
-    data.unref().unwrap();
-    let _ = Box::from_raw(handle);
+    // the data is null if init() is never called
+    let data = (*handle).data as *mut HandleData;
+    if !data.is_null() {
+      let data = Box::from_raw(data);
+      let _ = data.unref();
+    }
+    let _ = Box::from_raw(handle as *mut sys::uv_poll_t);
   };
 }
 
@@ -623,13 +1195,17 @@ macro_rules! on_event {
         return;
       }
 
-      unsafe {
-        assert!(!(*handle).data.is_null(), "unexpected null handle data");
-      };
-      let data = unsafe { Box::from_raw((*handle).data as *mut HandleData) };
-      let wrap = data.inner_mut_ref::<&mut SeqpacketSocketWrap>().unwrap();
-      wrap.$fn(status, events);
-      Box::into_raw(data);
+      // Only borrow the handle data here. Callbacks might close the socket
+      // and the data is freed in on_close().
+      let data = unsafe { (*handle).data as *const HandleData };
+      if data.is_null() {
+        return;
+      }
+      let data = unsafe { &*data };
+      // the object can't be recovered if the environment is being torn down
+      if let Ok(wrap) = data.inner_ref::<&SeqpacketSocketWrap>() {
+        wrap.$fn(status, events);
+      }
     }
   };
 }
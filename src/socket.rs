@@ -1,13 +1,27 @@
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
 use std::ffi::CString;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
 use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::process;
+use std::ptr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::util::{error, get_err, resolve_libc_err, resolve_uv_err};
+use crate::reuseport::{self, ReuseportOptions};
+use crate::util::{create_errno_error, error, get_err, resolve_libc_err, resolve_uv_err, uv_err};
+use crate::uv_handle;
 use libc::{c_void, sockaddr_storage, sockaddr_un};
 use napi::{
-  bindgen_prelude::FromNapiValue, Env, JsFunction, JsNumber, JsObject, JsString, JsUnknown, Ref,
-  Result,
+  bindgen_prelude::FromNapiValue, Env, JsFunction, JsNumber, JsObject, JsString, JsUnknown,
+  NapiRaw, NapiValue, Ref, Result,
 };
+use nix::errno::errno;
 use uv_sys::sys;
 
 pub(crate) fn get_loop(env: &Env) -> Result<*mut sys::uv_loop_t> {
@@ -18,10 +32,8 @@ pub(crate) fn close(fd: i32) -> Result<()> {
   let ret = unsafe { libc::close(fd) };
 
   // TODO should we loop?
-  if ret != 0 {
-    if ret != libc::EINTR && ret != libc::EINPROGRESS {
-      return Err(get_err());
-    }
+  if ret != 0 && ret != libc::EINTR && ret != libc::EINPROGRESS {
+    return Err(get_err());
   }
 
   Ok(())
@@ -58,72 +70,534 @@ pub(crate) fn sockaddr_from_string(bytes: &str) -> Result<(sockaddr_un, usize)>
   Ok((sockaddr, mem::size_of::<sockaddr_un>()))
 }
 
-pub(crate) struct Emitter {
-  env: Env,
-  emit_ref: Option<Ref<()>>,
+/**
+ * Options of binding a unix socket to a path.
+ */
+#[napi(object)]
+#[derive(Default)]
+pub struct BindOptions {
+  /**
+   * Bind to a temporary sibling path and rename() it over the target path
+   * once the socket is ready.
+   */
+  pub atomic: Option<bool>,
+  /**
+   * Take an flock() on "<path>.lock" and hold it until the socket gets
+   * closed. Binding fails if another process holds the lock.
+   */
+  pub lock: Option<bool>,
+  /**
+   * Remove the socket file if the socket is still open when its environment,
+   * e.g. a worker, is torn down. The file is kept if it has been replaced by
+   * another socket.
+   */
+  pub unlink_on_teardown: Option<bool>,
 }
 
-impl Drop for Emitter {
+/**
+ * A socket file that we bound. It's identified by its inode so that we never
+ * remove a file that replaced it.
+ */
+pub(crate) struct BoundPath {
+  path: String,
+  dev: u64,
+  ino: u64,
+}
+
+impl BoundPath {
+  pub fn new(path: &str) -> Result<Self> {
+    let meta = fs::symlink_metadata(path).map_err(error)?;
+
+    Ok(Self {
+      path: path.to_string(),
+      dev: meta.dev(),
+      ino: meta.ino(),
+    })
+  }
+
+  /**
+   * Returns the path to unlink on teardown if `options` asks for it. Abstract
+   * socket addresses have no files.
+   */
+  pub fn from_options(path: &str, options: &BindOptions) -> Option<Self> {
+    if !options.unlink_on_teardown.unwrap_or(false) || path.starts_with('\0') {
+      return None;
+    }
+
+    Self::new(path).ok()
+  }
+
+  pub fn unlink(&self) {
+    if let Ok(meta) = fs::symlink_metadata(&self.path) {
+      if meta.dev() == self.dev && meta.ino() == self.ino {
+        let _ = fs::remove_file(&self.path);
+      }
+    }
+  }
+}
+
+/**
+ * An exclusive flock() on the companion lock file of a socket path. The lock
+ * is released when it gets dropped.
+ */
+pub(crate) struct PathLock {
+  fd: i32,
+}
+
+impl PathLock {
+  pub fn acquire(bindpath: &str) -> Result<Self> {
+    if bindpath.starts_with('\0') {
+      return Err(error(
+        "locking doesn't support abstract socket addresses".to_string(),
+      ));
+    }
+
+    let lock_path = format!("{}.lock", bindpath);
+    let c_path = CString::new(lock_path.as_bytes())?;
+    let fd = resolve_libc_err(unsafe {
+      libc::open(
+        c_path.as_ptr(),
+        libc::O_RDWR | libc::O_CREAT | libc::O_CLOEXEC,
+        0o644,
+      )
+    })?;
+
+    let ret = unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) };
+    if ret == -1 {
+      let code = errno();
+      let err = get_err();
+      let _ = close(fd);
+      if code == libc::EWOULDBLOCK {
+        return Err(error(format!(
+          "'{}' is locked by another process",
+          lock_path
+        )));
+      }
+      return Err(err);
+    }
+
+    Ok(Self { fd })
+  }
+}
+
+impl Drop for PathLock {
   fn drop(&mut self) {
-    self.unref().unwrap();
+    // closing the fd releases the lock
+    let _ = close(self.fd);
   }
 }
 
 /**
- * Helper to use the emit() of js "EventEmitter".
+ * Remove a socket file left by a process that is not running anymore. Only
+ * call it with the PathLock of `bindpath` held.
  */
-impl Emitter {
-  pub fn new(env: Env, emit: JsFunction) -> Result<Self> {
-    let emit_ref = env.create_reference(emit)?;
+fn remove_stale_socket(bindpath: &str) -> Result<()> {
+  match fs::symlink_metadata(bindpath) {
+    Ok(meta) => {
+      if meta.file_type().is_socket() {
+        fs::remove_file(bindpath).map_err(error)?;
+      }
+      Ok(())
+    }
+    Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+    Err(e) => Err(error(e)),
+  }
+}
+
+static TMP_PATH_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+fn sun_path_len() -> usize {
+  let sockaddr = unsafe { mem::MaybeUninit::<sockaddr_un>::zeroed().assume_init() };
+  mem::size_of_val(&sockaddr.sun_path)
+}
+
+/**
+ * A temporary path in the directory of `bindpath` so that rename() never
+ * crosses filesystems. Paths that don't fit `sun_path` with the suffix fall
+ * back to a short name made of a hash of `bindpath`.
+ */
+fn tmp_sibling_path(bindpath: &str) -> Result<String> {
+  let seq = TMP_PATH_SEQ.fetch_add(1, Ordering::Relaxed);
+  let suffix = format!("{}.{}.tmp", process::id(), seq);
+  // there should be room for the trailing NUL
+  let fits = |path: &str| path.len() < sun_path_len();
+
+  let tmp_path = format!("{}.{}", bindpath, suffix);
+  if fits(&tmp_path) {
+    return Ok(tmp_path);
+  }
+
+  let mut hasher = DefaultHasher::new();
+  bindpath.hash(&mut hasher);
+  let name = format!(".{:08x}.{}", hasher.finish() as u32, suffix);
+  let tmp_path = match Path::new(bindpath).parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir.join(name).to_string_lossy().into_owned(),
+    _ => name,
+  };
+  if !fits(&tmp_path) {
+    return Err(error(
+      "path to bind is too long for atomic binding".to_string(),
+    ));
+  }
+
+  Ok(tmp_path)
+}
+
+pub(crate) fn bind_path(fd: i32, bindpath: &str) -> Result<()> {
+  let (sockaddr, addr_len) = sockaddr_from_string(bindpath)?;
+  resolve_libc_err(unsafe {
+    libc::bind(
+      fd,
+      &sockaddr as *const _ as *const libc::sockaddr,
+      addr_len as u32,
+    )
+  })?;
+
+  Ok(())
+}
+
+/**
+ * Bind `fd` to a temporary sibling of `bindpath`, call `before_publish()` and
+ * then rename() the temporary file over `bindpath`. Clients will never see a
+ * missing or half-ready socket file at `bindpath`.
+ */
+pub(crate) fn bind_path_atomic<F>(fd: i32, bindpath: &str, before_publish: F) -> Result<()>
+where
+  F: FnOnce() -> Result<()>,
+{
+  if bindpath.starts_with('\0') {
+    return Err(error(
+      "atomic binding doesn't support abstract socket addresses".to_string(),
+    ));
+  }
+
+  // the temporary path might fit while `bindpath` doesn't
+  sockaddr_from_string(bindpath)?;
+  let tmp_path = tmp_sibling_path(bindpath)?;
+  bind_path(fd, &tmp_path)?;
+
+  let ret = before_publish().and_then(|_| fs::rename(&tmp_path, bindpath).map_err(error));
+  if ret.is_err() {
+    let _ = fs::remove_file(&tmp_path);
+  }
+
+  ret
+}
+
+/**
+ * Bind `fd` to `bindpath` according to `options`. `before_publish()` is
+ * called once the socket is bound and before it's visible at `bindpath` if
+ * `options.atomic` is set. Returns the lock that should be kept as long as
+ * the socket is open.
+ */
+pub(crate) fn bind_with_options<F>(
+  fd: i32,
+  bindpath: &str,
+  options: &BindOptions,
+  before_publish: F,
+) -> Result<Option<PathLock>>
+where
+  F: FnOnce() -> Result<()>,
+{
+  let atomic = options.atomic.unwrap_or(false);
+  let lock = match options.lock.unwrap_or(false) {
+    true => {
+      let lock = PathLock::acquire(bindpath)?;
+      if !atomic {
+        remove_stale_socket(bindpath)?;
+      }
+      Some(lock)
+    }
+    false => None,
+  };
+
+  if atomic {
+    bind_path_atomic(fd, bindpath, before_publish)?;
+  } else {
+    bind_path(fd, bindpath)?;
+    before_publish()?;
+  }
+
+  Ok(lock)
+}
+
+fn check_napi(status: napi::sys::napi_status) -> Result<()> {
+  if status == napi::sys::Status::napi_ok {
+    return Ok(());
+  }
+
+  Err(napi::Error::from_status(napi::Status::from(status)))
+}
+
+/**
+ * Exceptions thrown by listeners or callbacks inside uv callbacks have no js
+ * caller to catch them. Pass them to `process.on('uncaughtException')` like
+ * Node.js does for its own handles. Returns whether there was one.
+ */
+pub(crate) fn trigger_pending_exception(env: Env) -> bool {
+  let raw_env = env.raw();
+  let mut pending = false;
+  let status = unsafe { napi::sys::napi_is_exception_pending(raw_env, &mut pending) };
+  if status != napi::sys::Status::napi_ok || !pending {
+    return false;
+  }
+
+  let _ = env.run_in_scope(|| {
+    let mut exception = ptr::null_mut();
+    check_napi(unsafe { napi::sys::napi_get_and_clear_last_exception(raw_env, &mut exception) })?;
+    check_napi(unsafe { napi::sys::napi_fatal_exception(raw_env, exception) })
+  });
+  true
+}
+
+/**
+ * Report a failure inside uv callbacks, where nothing should be thrown or
+ * left pending. Native errors are emitted as "_error" events and exceptions
+ * go to `uncaughtException`.
+ */
+pub(crate) fn emit_error(env: Env, emitter: &Emitter, err: napi::Error) {
+  if trigger_pending_exception(env) {
+    return;
+  }
+
+  // emitting fails if the environment is being torn down
+  let _ = env.run_in_scope(|| {
+    let event = env.create_string("_error")?;
+    let error = env.create_error(err)?;
+    emitter.emit(&[event.into_unknown(), error.into_unknown()])
+  });
+  // e.g. there is no listener of "error"
+  trigger_pending_exception(env);
+}
+
+/**
+ * A reference to a js value that can be switched between strong and weak.
+ * `Ref` of napi-rs deletes the reference once its count drops to zero so
+ * that we use napi_ref directly.
+ */
+pub(crate) struct JsRef {
+  env: Env,
+  raw_ref: Cell<napi::sys::napi_ref>,
+  strong: Cell<bool>,
+}
+
+impl JsRef {
+  pub fn new<T: NapiRaw>(env: Env, value: T) -> Result<Self> {
+    let mut raw_ref = ptr::null_mut();
+    check_napi(unsafe {
+      napi::sys::napi_create_reference(env.raw(), value.raw(), 1, &mut raw_ref)
+    })?;
 
     Ok(Self {
       env,
-      emit_ref: Some(emit_ref),
+      raw_ref: Cell::new(raw_ref),
+      strong: Cell::new(true),
     })
   }
 
-  pub fn unref(&mut self) -> Result<()> {
-    let mut emit_ref = self.emit_ref.take();
+  /**
+   * Returns None if the reference has been deleted or the value has been
+   * garbage-collected.
+   */
+  pub fn get<T: NapiValue>(&self) -> Result<Option<T>> {
+    let raw_ref = self.raw_ref.get();
+    if raw_ref.is_null() {
+      return Ok(None);
+    }
 
-    match emit_ref.as_mut() {
-      None => (),
-      Some(emit_ref) => {
-        emit_ref.unref(self.env)?;
-      }
+    let mut value = ptr::null_mut();
+    check_napi(unsafe {
+      napi::sys::napi_get_reference_value(self.env.raw(), raw_ref, &mut value)
+    })?;
+    if value.is_null() {
+      return Ok(None);
     }
 
-    Ok(())
+    Ok(Some(unsafe {
+      T::from_raw_unchecked(self.env.raw(), value)
+    }))
   }
 
-  fn check_ref(&self) -> Result<()> {
-    if self.emit_ref.is_none() {
-      return Err(error("emitter already unreferenced".to_string()));
+  pub fn set_strong(&self, strong: bool) -> Result<()> {
+    let raw_ref = self.raw_ref.get();
+    if raw_ref.is_null() || self.strong.get() == strong {
+      return Ok(());
     }
 
+    let mut count = 0;
+    check_napi(unsafe {
+      if strong {
+        napi::sys::napi_reference_ref(self.env.raw(), raw_ref, &mut count)
+      } else {
+        napi::sys::napi_reference_unref(self.env.raw(), raw_ref, &mut count)
+      }
+    })?;
+    self.strong.set(strong);
+
     Ok(())
   }
 
-  pub fn emit(&mut self, args: &[JsUnknown]) -> Result<()> {
-    self.check_ref()?;
+  pub fn delete(&self) -> Result<()> {
+    let raw_ref = self.raw_ref.replace(ptr::null_mut());
+    if raw_ref.is_null() {
+      return Ok(());
+    }
 
-    let env = self.env;
+    check_napi(unsafe { napi::sys::napi_delete_reference(self.env.raw(), raw_ref) })
+  }
+}
+
+/**
+ * An async resource of napi so that js callbacks of a socket run in the
+ * async context, e.g. the `AsyncLocalStorage`, where the socket is created,
+ * and `process.nextTick()` callbacks and microtasks run right after them
+ * like those of Node.js sockets.
+ */
+pub(crate) struct AsyncResource {
+  env: Env,
+  context: Cell<napi::sys::napi_async_context>,
+}
+
+impl AsyncResource {
+  /**
+   * A null `resource` lets Node.js create one.
+   */
+  pub fn new(env: Env, resource: Option<&JsObject>, name: &str) -> Result<Self> {
+    let resource = match resource {
+      Some(resource) => unsafe { resource.raw() },
+      None => ptr::null_mut(),
+    };
+    let name = env.create_string(name)?;
+    let mut context = ptr::null_mut();
+    check_napi(unsafe {
+      napi::sys::napi_async_init(env.raw(), resource, name.raw(), &mut context)
+    })?;
+
+    Ok(Self {
+      env,
+      context: Cell::new(context),
+    })
+  }
+
+  /**
+   * Call `func` with napi_make_callback(). Exceptions thrown by it are left
+   * pending, which napi-rs would clear. Methods called from js rethrow them
+   * to the caller as is, and uv callbacks pass them to
+   * `trigger_pending_exception()`.
+   */
+  pub fn make_callback(&self, func: &JsFunction, args: &[JsUnknown]) -> Result<()> {
+    let context = self.context.get();
+    if context.is_null() {
+      return Err(error("async resource already destroyed".to_string()));
+    }
+
+    let raw_env = self.env.raw();
+    // napi_make_callback() requires an object as `this`
+    let mut this = ptr::null_mut();
+    check_napi(unsafe { napi::sys::napi_get_global(raw_env, &mut this) })?;
+    let raw_args = args
+      .iter()
+      .map(|arg| unsafe { arg.raw() })
+      .collect::<Vec<_>>();
+    let mut ret = ptr::null_mut();
+    check_napi(unsafe {
+      napi::sys::napi_make_callback(
+        raw_env,
+        context,
+        this,
+        func.raw(),
+        raw_args.len(),
+        raw_args.as_ptr(),
+        &mut ret,
+      )
+    })
+  }
+
+  pub fn destroy(&self) -> Result<()> {
+    let context = self.context.replace(ptr::null_mut());
+    if context.is_null() {
+      return Ok(());
+    }
+
+    check_napi(unsafe { napi::sys::napi_async_destroy(self.env.raw(), context) })
+  }
+}
+
+impl Drop for AsyncResource {
+  fn drop(&mut self) {
+    let _ = self.destroy();
+  }
+}
+
+pub(crate) struct Emitter {
+  emit_ref: JsRef,
+  async_resource: AsyncResource,
+}
+
+impl Drop for Emitter {
+  fn drop(&mut self) {
+    // it might be dropped in finalizers so that errors are ignored
+    let _ = self.unref();
+  }
+}
+
+/**
+ * Helper to use the emit() of js "EventEmitter".
+ */
+impl Emitter {
+  /**
+   * `name` is the type of the async resource, e.g. `'DgramSocket'`.
+   */
+  pub fn new(env: Env, ee: &JsObject, name: &str) -> Result<Self> {
+    let emit: JsFunction = ee.get_named_property("emit")?;
+    let emit_ref = JsRef::new(env, emit)?;
+    let async_resource = AsyncResource::new(env, Some(ee), name)?;
+
+    Ok(Self {
+      emit_ref,
+      async_resource,
+    })
+  }
+
+  pub fn unref(&self) -> Result<()> {
+    self.async_resource.destroy()?;
+    self.emit_ref.delete()
+  }
+
+  /**
+   * A weak emitter doesn't keep the "EventEmitter" alive.
+   */
+  pub fn set_strong(&self, strong: bool) -> Result<()> {
+    self.emit_ref.set_strong(strong)
+  }
+
+  pub fn emit(&self, args: &[JsUnknown]) -> Result<()> {
+    let env = self.emit_ref.env;
 
     env.run_in_scope(|| {
-      let emit_ref = self.emit_ref.as_mut().unwrap();
-      let emit: JsFunction = env.get_reference_value(emit_ref)?;
-      emit.call(None, args)?;
-      Ok(())
+      let emit: JsFunction = match self.emit_ref.get()? {
+        Some(emit) => emit,
+        None => return Err(error("emitter already unreferenced".to_string())),
+      };
+      self.async_resource.make_callback(&emit, args)
     })?;
 
     Ok(())
   }
 
-  pub fn emit_event(&mut self, event: &str) -> Result<()> {
-    let env = self.env;
+  /**
+   * Call other callbacks of the socket, e.g. those of `write()`, in its
+   * async context.
+   */
+  pub fn make_callback(&self, func: &JsFunction, args: &[JsUnknown]) -> Result<()> {
+    self.async_resource.make_callback(func, args)
+  }
+
+  pub fn emit_event(&self, event: &str) -> Result<()> {
+    let env = self.emit_ref.env;
     env.run_in_scope(|| {
       let js_event = env.create_string(event)?;
-      let mut args: Vec<JsUnknown> = vec![];
-      args.push(js_event.into_unknown());
+      let args: Vec<JsUnknown> = vec![js_event.into_unknown()];
 
       self.emit(&args)
     })?;
@@ -131,21 +605,34 @@ impl Emitter {
   }
 }
 
+/**
+ * The data of a uv handle. The handle and its data are only freed in the
+ * close callback of uv_close(), which libuv calls in a later iteration of the
+ * loop, so that they stay valid while js callbacks close the socket.
+ */
 pub(crate) struct HandleData {
   env: Env,
-  this_ref: Ref<()>,
+  this_ref: JsRef,
 }
 
 impl HandleData {
   pub fn new(env: Env, this: JsObject) -> Result<Self> {
-    let this_ref = env.create_reference(this)?;
+    let this_ref = JsRef::new(env, this)?;
     Ok(HandleData { env, this_ref })
   }
 
-  pub fn inner_mut_ref<'a, T: FromNapiValue>(&'a self) -> Result<T> {
+  /**
+   * Get the wrap of the js object, e.g. `&SeqpacketSocketWrap`. Only shared
+   * references are handed out because js callbacks might get another one
+   * while it's in use.
+   */
+  pub fn inner_ref<T: FromNapiValue>(&self) -> Result<T> {
     let env = self.env;
     let native = env.run_in_scope(|| {
-      let obj: JsObject = self.env.get_reference_value(&self.this_ref)?;
+      let obj: JsObject = match self.this_ref.get()? {
+        Some(obj) => obj,
+        None => return Err(error("object has been collected".to_string())),
+      };
       let native: T = FromNapiValue::from_unknown(obj.into_unknown())?;
       // let native: &mut T = self.env.unwrap(&obj)?;
       Ok(native)
@@ -153,63 +640,216 @@ impl HandleData {
     Ok(native)
   }
 
-  pub fn unref(&mut self) -> Result<()> {
-    let env = self.env;
-    self.this_ref.unref(env)?;
-    Ok(())
+  pub fn set_strong(&self, strong: bool) -> Result<()> {
+    self.this_ref.set_strong(strong)
+  }
+
+  pub fn unref(&self) -> Result<()> {
+    self.this_ref.delete()
+  }
+}
+
+/**
+ * Keep the js objects of a socket alive only while its uv handle is active,
+ * i.e. it's listening, connecting, reading or writing, so that idle sockets
+ * can be garbage-collected and closed in their finalizers.
+ */
+pub(crate) fn update_refs(handle: *mut sys::uv_poll_t, emitter: &Emitter) -> Result<()> {
+  if handle.is_null() {
+    return Ok(());
+  }
+
+  let active = unsafe { sys::uv_is_active(handle as *const _) } != 0;
+  emitter.set_strong(active)?;
+  let data = unsafe { (*handle).data as *const HandleData };
+  if !data.is_null() {
+    unsafe { (*data).set_strong(active)? };
+  }
+
+  Ok(())
+}
+
+/**
+ * Delete the reference of the handle data right away instead of in the
+ * close callback, which might run after the environment is gone.
+ */
+pub(crate) fn release_handle_data(handle: *mut sys::uv_poll_t) {
+  if handle.is_null() {
+    return;
+  }
+
+  let data = unsafe { (*handle).data as *const HandleData };
+  if !data.is_null() {
+    let _ = unsafe { (*data).unref() };
+  }
+}
+
+/**
+ * Called in finalizers of sockets that are garbage-collected without being
+ * closed.
+ */
+pub(crate) fn warn_unclosed(env: Env, name: &str, fd: i32) {
+  if uv_handle::unclosed_warning(env) {
+    eprintln!(
+      "(node-unix-socket) Warning: a {} (fd: {}) was garbage-collected without being closed",
+      name, fd
+    );
+  }
+}
+
+/**
+ * Call the callback of a message with `null` if it's sent or an error of
+ * `errno` otherwise, e.g. ECANCELED if the socket is closed before that.
+ * The reference is always released so that each callback is called once.
+ */
+pub(crate) fn call_write_cb(
+  env: Env,
+  emitter: &Emitter,
+  mut cb: Ref<()>,
+  errno: Option<i32>,
+) -> Result<()> {
+  let ret = (|| {
+    let arg = match errno {
+      Some(errno) => create_errno_error(env, errno)?.into_unknown(),
+      None => env.get_null()?.into_unknown(),
+    };
+    let cb: JsFunction = env.get_reference_value(&cb)?;
+    emitter.make_callback(&cb, &[arg])
+  })();
+
+  cb.unref(env)?;
+  ret
+}
+
+/**
+ * Keep the first error of a series of js calls, clearing exceptions they
+ * throw so that the rest can still be called. `into_result()` throws the
+ * exception again.
+ */
+pub(crate) struct FirstError {
+  env: Env,
+  first: Option<Result<napi::sys::napi_value>>,
+}
+
+impl FirstError {
+  pub fn new(env: Env) -> Self {
+    Self { env, first: None }
+  }
+
+  pub fn check(&mut self, ret: Result<()>) {
+    let err = match ret {
+      Ok(()) => return,
+      Err(err) => err,
+    };
+
+    let raw_env = self.env.raw();
+    let mut pending = false;
+    let mut exception = ptr::null_mut();
+    let caught = unsafe {
+      napi::sys::napi_is_exception_pending(raw_env, &mut pending) == napi::sys::Status::napi_ok
+        && pending
+        && napi::sys::napi_get_and_clear_last_exception(raw_env, &mut exception)
+          == napi::sys::Status::napi_ok
+    };
+    if self.first.is_none() {
+      self.first = Some(if caught { Ok(exception) } else { Err(err) });
+    }
+  }
+
+  pub fn into_result(self) -> Result<()> {
+    match self.first {
+      None => Ok(()),
+      Some(Err(err)) => Err(err),
+      Some(Ok(exception)) => {
+        check_napi(unsafe { napi::sys::napi_throw(self.env.raw(), exception) })?;
+        Err(napi::Error::from_status(napi::Status::PendingException))
+      }
+    }
+  }
+}
+
+/**
+ * Call callbacks of messages dropped by `close()` with ECANCELED. Every
+ * callback is called even if some of them throw.
+ */
+pub(crate) fn cancel_write_cbs(env: Env, emitter: &Emitter, cbs: Vec<Ref<()>>) -> FirstError {
+  let mut first_error = FirstError::new(env);
+  for cb in cbs {
+    first_error.check(call_write_cb(env, emitter, cb, Some(libc::ECANCELED)));
+  }
+
+  first_error
+}
+
+/**
+ * Release callbacks of dropped messages without calling them, which is
+ * only used where js can't be called, e.g. in finalizers.
+ */
+pub(crate) fn drop_write_cbs(env: Env, cbs: Vec<Ref<()>>) {
+  for mut cb in cbs {
+    let _ = cb.unref(env);
   }
 }
 
 /**
  * Add references to prevent Node.js from automatically exiting if there is
  * no references in the loop.
+ *
+ * `get_handle()` returns null once the handle has been passed to uv_close().
  */
 pub trait UvRefence {
   fn get_handle(&self) -> *mut sys::uv_poll_t;
 
   fn refer(&self) {
     let ptr = self.get_handle();
+    if ptr.is_null() {
+      return;
+    }
 
     unsafe { sys::uv_ref(ptr as *mut _ as *mut sys::uv_handle_t) }
   }
 
   fn unref(&self) {
     let ptr = self.get_handle();
+    if ptr.is_null() {
+      return;
+    }
 
     unsafe { sys::uv_unref(ptr as *mut _ as *mut sys::uv_handle_t) }
   }
 }
 
-fn bind_socket(env: Env, fd: i32, domain: i32, port: JsNumber, ip: JsString) -> Result<JsNumber> {
-  let mut on: i32 = 1;
+pub(crate) fn set_int_sockopt(fd: i32, level: i32, name: i32, val: i32) -> Result<()> {
+  let mut val = val;
   resolve_libc_err(unsafe {
     libc::setsockopt(
       fd,
-      libc::SOL_SOCKET,
-      libc::SO_REUSEADDR,
-      &mut on as *mut _ as *mut c_void,
+      level,
+      name,
+      &mut val as *mut _ as *mut c_void,
       mem::size_of::<i32>() as u32,
     )
   })?;
 
-  let mut on: i32 = 1;
-  resolve_libc_err(unsafe {
-    libc::setsockopt(
-      fd,
-      libc::SOL_SOCKET,
-      libc::SO_REUSEPORT,
-      &mut on as *mut _ as *mut c_void,
-      mem::size_of::<i32>() as u32,
-    )
-  })?;
+  Ok(())
+}
+
+fn bind_socket(
+  fd: i32,
+  domain: i32,
+  ty: i32,
+  port: i32,
+  ip: &str,
+  options: &ReuseportOptions,
+) -> Result<()> {
+  set_int_sockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+  set_int_sockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+  reuseport::before_bind(fd, domain, ty, options)?;
 
   // parse ip port
-  let ip = ip.into_utf8()?;
-  let ip_str = CString::new(ip.as_str()?.to_string().into_bytes())?;
+  let ip_str = CString::new(ip.as_bytes())?;
   let mut addr = unsafe { mem::MaybeUninit::<sockaddr_storage>::zeroed().assume_init() };
-  let addr_len: u32;
-  let port = port.get_int32()?;
-  if domain == libc::AF_INET {
+  let addr_len: u32 = if domain == libc::AF_INET {
     resolve_uv_err(unsafe {
       sys::uv_ip4_addr(
         ip_str.as_c_str().as_ptr(),
@@ -217,7 +857,7 @@ fn bind_socket(env: Env, fd: i32, domain: i32, port: JsNumber, ip: JsString) ->
         &mut addr as *mut _ as *mut sys::sockaddr_in,
       )
     })?;
-    addr_len = mem::size_of::<sys::sockaddr_in>() as u32;
+    mem::size_of::<sys::sockaddr_in>() as u32
   } else {
     resolve_uv_err(unsafe {
       sys::uv_ip6_addr(
@@ -226,28 +866,30 @@ fn bind_socket(env: Env, fd: i32, domain: i32, port: JsNumber, ip: JsString) ->
         &mut addr as *mut _ as *mut sys::sockaddr_in6,
       )
     })?;
-    addr_len = mem::size_of::<sys::sockaddr_in6>() as u32;
+    mem::size_of::<sys::sockaddr_in6>() as u32
   };
 
   // bind socket
   resolve_libc_err(unsafe {
     libc::bind(fd, &mut addr as *mut _ as *mut libc::sockaddr, addr_len)
   })?;
+  reuseport::after_bind(fd, ty, options)?;
 
-  Ok(env.create_int32(fd)?)
+  Ok(())
 }
 
-#[allow(dead_code)]
-#[napi]
-fn socket_new_so_reuseport_fd(
-  env: Env,
-  domain: JsString,
-  port: JsNumber,
-  ip: JsString,
-) -> Result<JsNumber> {
-  let domain = domain.into_utf8()?;
-  let s = domain.as_str()?;
-  let domain = match s {
+/**
+ * Create a socket with SO_REUSEPORT enabled and bind it to `ip:port`.
+ * `domain` should be "ipv4" or "ipv6".
+ */
+pub(crate) fn new_reuseport_socket(
+  domain: &str,
+  ty: i32,
+  port: i32,
+  ip: &str,
+  options: &ReuseportOptions,
+) -> Result<i32> {
+  let domain = match domain {
     "ipv4" => libc::AF_INET,
     "ipv6" => libc::AF_INET6,
     _ => {
@@ -257,18 +899,217 @@ fn socket_new_so_reuseport_fd(
     }
   };
 
+  // uv_ip4_addr() and uv_ip6_addr() truncate it silently
+  if !(0..=u16::MAX as i32).contains(&port) {
+    return Err(error(format!(
+      "port should be in 0 to {}, got {}",
+      u16::MAX,
+      port
+    )));
+  }
+
   // create socket and set SO_REUSEPORT
-  let fd = resolve_libc_err(unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) })?;
+  let fd = resolve_libc_err(unsafe { libc::socket(domain, ty, 0) })?;
+
+  if let Err(e) = bind_socket(fd, domain, ty, port, ip, options) {
+    close(fd)?;
+    return Err(e);
+  }
+
+  Ok(fd)
+}
+
+fn new_reuseport_fd(
+  env: Env,
+  domain: JsString,
+  ty: i32,
+  port: JsNumber,
+  ip: JsString,
+  options: Option<ReuseportOptions>,
+) -> Result<JsNumber> {
+  let options = options.unwrap_or_default();
+  let domain = domain.into_utf8()?;
+  let ip = ip.into_utf8()?;
+  let fd = new_reuseport_socket(
+    domain.as_str()?,
+    ty,
+    port.get_int32()?,
+    ip.as_str()?,
+    &options,
+  )?;
+
+  env.create_int32(fd)
+}
+
+#[allow(dead_code)]
+#[napi]
+fn socket_new_so_reuseport_fd(
+  env: Env,
+  domain: JsString,
+  port: JsNumber,
+  ip: JsString,
+  options: Option<ReuseportOptions>,
+) -> Result<JsNumber> {
+  new_reuseport_fd(env, domain, libc::SOCK_STREAM, port, ip, options)
+}
+
+#[allow(dead_code)]
+#[napi]
+fn socket_new_so_reuseport_udp_fd(
+  env: Env,
+  domain: JsString,
+  port: JsNumber,
+  ip: JsString,
+  options: Option<ReuseportOptions>,
+) -> Result<JsNumber> {
+  new_reuseport_fd(env, domain, libc::SOCK_DGRAM, port, ip, options)
+}
+
+/**
+ * The address of an IP socket, like the `net.AddressInfo` of Node.js.
+ */
+#[napi(object)]
+pub struct AddressInfo {
+  pub address: String,
+  pub family: String,
+  pub port: u32,
+}
+
+/**
+ * Convert a sockaddr_in or sockaddr_in6 into `AddressInfo`.
+ */
+pub(crate) unsafe fn inet_addr_info(addr: *const libc::sockaddr) -> Result<AddressInfo> {
+  match (*addr).sa_family as i32 {
+    libc::AF_INET => {
+      let addr = &*(addr as *const libc::sockaddr_in);
+      Ok(AddressInfo {
+        address: Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).to_string(),
+        family: "IPv4".to_string(),
+        port: u16::from_be(addr.sin_port) as u32,
+      })
+    }
+    libc::AF_INET6 => {
+      let addr = &*(addr as *const libc::sockaddr_in6);
+      Ok(AddressInfo {
+        address: Ipv6Addr::from(addr.sin6_addr.s6_addr).to_string(),
+        family: "IPv6".to_string(),
+        port: u16::from_be(addr.sin6_port) as u32,
+      })
+    }
+    family => Err(error(format!("unexpected address family {}", family))),
+  }
+}
+
+pub(crate) fn inet_socket_address(fd: i32) -> Result<AddressInfo> {
+  let mut addr = unsafe { mem::MaybeUninit::<sockaddr_storage>::zeroed().assume_init() };
+  let mut addr_len = mem::size_of::<sockaddr_storage>() as u32;
+  resolve_libc_err(unsafe {
+    libc::getsockname(
+      fd,
+      &mut addr as *mut _ as *mut libc::sockaddr,
+      &mut addr_len as *mut _,
+    )
+  })?;
+
+  unsafe { inet_addr_info(&addr as *const _ as *const libc::sockaddr) }
+}
+
+struct LookupData {
+  env: Env,
+  cb: Ref<()>,
+  async_resource: AsyncResource,
+}
+
+fn lookup_result(env: Env, status: i32, res: *const libc::addrinfo) -> Result<Vec<JsUnknown>> {
+  if status < 0 {
+    let err = env.create_error(uv_err(status))?;
+    return Ok(vec![err.into_unknown()]);
+  }
 
-  let fd = match bind_socket(env, fd, domain, port, ip) {
-    Ok(fd) => fd,
-    Err(e) => {
-      close(fd).unwrap();
-      return Err(e);
+  let mut addresses = env.create_array_with_length(0)?;
+  let mut idx = 0;
+  let mut info = res;
+  while !info.is_null() {
+    let addr = unsafe { (*info).ai_addr as *const libc::sockaddr };
+    if !addr.is_null() {
+      if let Ok(addr) = unsafe { inet_addr_info(addr) } {
+        let mut item = env.create_object()?;
+        item.set_named_property("address", env.create_string(&addr.address)?)?;
+        item.set_named_property("family", env.create_string(&addr.family)?)?;
+        addresses.set_element(idx, item)?;
+        idx += 1;
+      }
     }
+    info = unsafe { (*info).ai_next };
+  }
+
+  Ok(vec![
+    env.get_null()?.into_unknown(),
+    addresses.into_unknown(),
+  ])
+}
+
+extern "C" fn on_lookup(req: *mut sys::uv_getaddrinfo_t, status: i32, res: *mut sys::addrinfo) {
+  let req = unsafe { Box::from_raw(req) };
+  let mut data = unsafe { Box::from_raw(req.data as *mut LookupData) };
+  let env = data.env;
+
+  let _ = env.run_in_scope(|| {
+    // the addrinfo of uv_sys follows the layout of macOS
+    let args = lookup_result(env, status, res as *const libc::addrinfo)?;
+    let cb: JsFunction = env.get_reference_value(&data.cb)?;
+    data.async_resource.make_callback(&cb, &args)
+  });
+  trigger_pending_exception(env);
+
+  let _ = data.cb.unref(env);
+  if !res.is_null() {
+    unsafe { sys::uv_freeaddrinfo(res) };
+  }
+}
+
+/**
+ * Resolve `host` with uv_getaddrinfo(). `cb` is called with an error or an
+ * array of `{ address, family }`.
+ */
+#[allow(dead_code)]
+#[napi]
+fn socket_lookup(env: Env, host: String, cb: JsFunction) -> Result<()> {
+  let uv_loop = get_loop(&env)?;
+  let node = CString::new(host.into_bytes())?;
+  let mut hints = unsafe { mem::MaybeUninit::<libc::addrinfo>::zeroed().assume_init() };
+  hints.ai_family = libc::AF_UNSPEC;
+  hints.ai_socktype = libc::SOCK_STREAM;
+
+  let data = Box::new(LookupData {
+    env,
+    cb: env.create_reference(cb)?,
+    async_resource: AsyncResource::new(env, None, "SocketLookup")?,
+  });
+  let req = Box::into_raw(Box::new(unsafe {
+    let mut req = mem::MaybeUninit::<sys::uv_getaddrinfo_t>::zeroed().assume_init();
+    req.data = Box::into_raw(data) as *mut _;
+    req
+  }));
+
+  let ret = unsafe {
+    sys::uv_getaddrinfo(
+      uv_loop,
+      req,
+      Some(on_lookup),
+      node.as_ptr(),
+      std::ptr::null(),
+      &hints as *const _ as *const sys::addrinfo,
+    )
   };
+  if ret < 0 {
+    let req = unsafe { Box::from_raw(req) };
+    let mut data = unsafe { Box::from_raw(req.data as *mut LookupData) };
+    data.cb.unref(env)?;
+    return Err(uv_err(ret));
+  }
 
-  Ok(fd)
+  Ok(())
 }
 
 #[allow(dead_code)]
@@ -278,3 +1119,13 @@ fn socket_close(fd: JsNumber) -> Result<()> {
 
   close(fd)
 }
+
+/**
+ * Print a warning when a socket of the current environment is
+ * garbage-collected without being closed.
+ */
+#[allow(dead_code)]
+#[napi]
+fn socket_set_unclosed_warning(env: Env, enabled: bool) -> Result<()> {
+  uv_handle::set_unclosed_warning(env, enabled)
+}
@@ -0,0 +1,293 @@
+use std::mem;
+
+use crate::util::{buf_data, error, resolve_libc_err};
+use libc::c_void;
+use napi::{
+  bindgen_prelude::FromNapiValue, Env, JsBuffer, JsNumber, JsUnknown, NapiRaw, Result, ValueType,
+};
+
+/**
+ * The value of SO_LINGER.
+ */
+#[napi(object)]
+pub struct Linger {
+  pub enabled: bool,
+  /**
+   * The linger time in seconds.
+   */
+  pub seconds: i32,
+}
+
+#[derive(Copy, Clone)]
+enum OptKind {
+  Int,
+  Bool,
+  /**
+   * struct timeval, represented as milliseconds in js.
+   */
+  Timeval,
+  Linger,
+}
+
+fn sol_socket_opt(name: &str) -> Result<(i32, OptKind)> {
+  let opt = match name {
+    "SO_RCVBUF" => (libc::SO_RCVBUF, OptKind::Int),
+    "SO_SNDBUF" => (libc::SO_SNDBUF, OptKind::Int),
+    "SO_RCVLOWAT" => (libc::SO_RCVLOWAT, OptKind::Int),
+    "SO_SNDLOWAT" => (libc::SO_SNDLOWAT, OptKind::Int),
+    "SO_ERROR" => (libc::SO_ERROR, OptKind::Int),
+    "SO_TYPE" => (libc::SO_TYPE, OptKind::Int),
+    "SO_DEBUG" => (libc::SO_DEBUG, OptKind::Bool),
+    "SO_DONTROUTE" => (libc::SO_DONTROUTE, OptKind::Bool),
+    "SO_BROADCAST" => (libc::SO_BROADCAST, OptKind::Bool),
+    "SO_KEEPALIVE" => (libc::SO_KEEPALIVE, OptKind::Bool),
+    "SO_OOBINLINE" => (libc::SO_OOBINLINE, OptKind::Bool),
+    "SO_REUSEADDR" => (libc::SO_REUSEADDR, OptKind::Bool),
+    "SO_REUSEPORT" => (libc::SO_REUSEPORT, OptKind::Bool),
+    "SO_ACCEPTCONN" => (libc::SO_ACCEPTCONN, OptKind::Bool),
+    "SO_RCVTIMEO" => (libc::SO_RCVTIMEO, OptKind::Timeval),
+    "SO_SNDTIMEO" => (libc::SO_SNDTIMEO, OptKind::Timeval),
+    "SO_LINGER" => (libc::SO_LINGER, OptKind::Linger),
+    _ => return platform_opt(name),
+  };
+
+  Ok(opt)
+}
+
+#[cfg(target_os = "linux")]
+fn platform_opt(name: &str) -> Result<(i32, OptKind)> {
+  let opt = match name {
+    "SO_RCVBUFFORCE" => (libc::SO_RCVBUFFORCE, OptKind::Int),
+    "SO_SNDBUFFORCE" => (libc::SO_SNDBUFFORCE, OptKind::Int),
+    "SO_PRIORITY" => (libc::SO_PRIORITY, OptKind::Int),
+    "SO_MARK" => (libc::SO_MARK, OptKind::Int),
+    "SO_PEEK_OFF" => (libc::SO_PEEK_OFF, OptKind::Int),
+    "SO_PASSCRED" => (libc::SO_PASSCRED, OptKind::Bool),
+    _ => return Err(unknown_opt(name)),
+  };
+
+  Ok(opt)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn platform_opt(name: &str) -> Result<(i32, OptKind)> {
+  Err(unknown_opt(name))
+}
+
+fn unknown_opt(name: &str) -> napi::Error {
+  error(format!("unknown socket option '{}'", name))
+}
+
+fn getsockopt<T>(fd: i32, level: i32, optname: i32, val: &mut T) -> Result<()> {
+  let mut len = mem::size_of::<T>() as u32;
+  resolve_libc_err(unsafe {
+    libc::getsockopt(
+      fd,
+      level,
+      optname,
+      val as *mut _ as *mut c_void,
+      &mut len as *mut _,
+    )
+  })?;
+
+  Ok(())
+}
+
+fn setsockopt<T>(fd: i32, level: i32, optname: i32, val: &T) -> Result<()> {
+  resolve_libc_err(unsafe {
+    libc::setsockopt(
+      fd,
+      level,
+      optname,
+      val as *const _ as *const c_void,
+      mem::size_of::<T>() as u32,
+    )
+  })?;
+
+  Ok(())
+}
+
+/**
+ * Get a SOL_SOCKET level option by its name, e.g. "SO_RCVBUF".
+ */
+pub(crate) fn get_sock_opt(env: Env, fd: i32, name: &str) -> Result<JsUnknown> {
+  let (optname, kind) = sol_socket_opt(name)?;
+  let level = libc::SOL_SOCKET;
+
+  match kind {
+    OptKind::Int => {
+      let mut val = 0_i32;
+      getsockopt(fd, level, optname, &mut val)?;
+      Ok(env.create_int32(val)?.into_unknown())
+    }
+    OptKind::Bool => {
+      let mut val = 0_i32;
+      getsockopt(fd, level, optname, &mut val)?;
+      Ok(env.get_boolean(val != 0)?.into_unknown())
+    }
+    OptKind::Timeval => {
+      let mut val = libc::timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+      };
+      getsockopt(fd, level, optname, &mut val)?;
+      let ms = val.tv_sec as f64 * 1000.0 + val.tv_usec as f64 / 1000.0;
+      Ok(env.create_double(ms)?.into_unknown())
+    }
+    OptKind::Linger => {
+      let mut val = libc::linger {
+        l_onoff: 0,
+        l_linger: 0,
+      };
+      getsockopt(fd, level, optname, &mut val)?;
+      let mut obj = env.create_object()?;
+      obj.set_named_property("enabled", env.get_boolean(val.l_onoff != 0)?)?;
+      obj.set_named_property("seconds", env.create_int32(val.l_linger)?)?;
+      Ok(obj.into_unknown())
+    }
+  }
+}
+
+fn expect_number(name: &str, value: JsUnknown) -> Result<JsNumber> {
+  if value.get_type()? != ValueType::Number {
+    return Err(error(format!("expect a number for '{}'", name)));
+  }
+
+  Ok(unsafe { value.cast::<JsNumber>() })
+}
+
+/**
+ * Set a SOL_SOCKET level option by its name. Timeouts are in milliseconds
+ * and SO_LINGER expects `{ enabled, seconds }`.
+ */
+pub(crate) fn set_sock_opt(env: Env, fd: i32, name: &str, value: JsUnknown) -> Result<()> {
+  let (optname, kind) = sol_socket_opt(name)?;
+  let level = libc::SOL_SOCKET;
+
+  match kind {
+    OptKind::Int => {
+      let val = expect_number(name, value)?.get_int32()?;
+      setsockopt(fd, level, optname, &val)
+    }
+    OptKind::Bool => {
+      let val: i32 = match value.get_type()? {
+        ValueType::Boolean => value.coerce_to_bool()?.get_value()? as i32,
+        ValueType::Number => (expect_number(name, value)?.get_int32()? != 0) as i32,
+        _ => return Err(error(format!("expect a boolean for '{}'", name))),
+      };
+      setsockopt(fd, level, optname, &val)
+    }
+    OptKind::Timeval => {
+      let ms = expect_number(name, value)?.get_double()?;
+      if ms < 0.0 {
+        return Err(error(format!("'{}' should not be negative", name)));
+      }
+      let sec = (ms / 1000.0).floor();
+      let val = libc::timeval {
+        tv_sec: sec as libc::time_t,
+        tv_usec: ((ms - sec * 1000.0) * 1000.0) as libc::suseconds_t,
+      };
+      setsockopt(fd, level, optname, &val)
+    }
+    OptKind::Linger => {
+      if value.get_type()? != ValueType::Object {
+        return Err(error(format!(
+          "expect {{ enabled, seconds }} for '{}'",
+          name
+        )));
+      }
+      let linger = unsafe { Linger::from_napi_value(env.raw(), value.raw())? };
+      let val = libc::linger {
+        l_onoff: linger.enabled as i32,
+        l_linger: linger.seconds,
+      };
+      setsockopt(fd, level, optname, &val)
+    }
+  }
+}
+
+/**
+ * Get an option by `level` and `optname`. At most `length` bytes are read.
+ */
+pub(crate) fn get_raw_sock_opt(
+  env: Env,
+  fd: i32,
+  level: i32,
+  optname: i32,
+  length: u32,
+) -> Result<JsBuffer> {
+  let mut buf = vec![0_u8; length as usize];
+  let mut len = length;
+  resolve_libc_err(unsafe {
+    libc::getsockopt(
+      fd,
+      level,
+      optname,
+      buf.as_mut_ptr() as *mut c_void,
+      &mut len as *mut _,
+    )
+  })?;
+  buf.truncate(len as usize);
+
+  Ok(env.create_buffer_with_data(buf)?.into_raw())
+}
+
+/**
+ * Set an option by `level` and `optname` with the bytes of `value`.
+ */
+pub(crate) fn set_raw_sock_opt(
+  env: Env,
+  fd: i32,
+  level: i32,
+  optname: i32,
+  value: JsBuffer,
+) -> Result<()> {
+  let value = buf_data(env, &value)?;
+  resolve_libc_err(unsafe {
+    libc::setsockopt(
+      fd,
+      level,
+      optname,
+      value.as_ptr() as *const c_void,
+      value.len() as u32,
+    )
+  })?;
+
+  Ok(())
+}
+
+#[allow(dead_code)]
+#[napi]
+fn socket_get_sock_opt(env: Env, fd: i32, name: String) -> Result<JsUnknown> {
+  get_sock_opt(env, fd, &name)
+}
+
+#[allow(dead_code)]
+#[napi]
+fn socket_set_sock_opt(env: Env, fd: i32, name: String, value: JsUnknown) -> Result<()> {
+  set_sock_opt(env, fd, &name, value)
+}
+
+#[allow(dead_code)]
+#[napi]
+fn socket_get_raw_sock_opt(
+  env: Env,
+  fd: i32,
+  level: i32,
+  optname: i32,
+  length: u32,
+) -> Result<JsBuffer> {
+  get_raw_sock_opt(env, fd, level, optname, length)
+}
+
+#[allow(dead_code)]
+#[napi]
+fn socket_set_raw_sock_opt(
+  env: Env,
+  fd: i32,
+  level: i32,
+  optname: i32,
+  value: JsBuffer,
+) -> Result<()> {
+  set_raw_sock_opt(env, fd, level, optname, value)
+}
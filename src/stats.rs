@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use libc::{EAGAIN, ENOBUFS, EWOULDBLOCK};
+
+/**
+ * A snapshot of the counters of a socket.
+ */
+#[napi(object)]
+pub struct SocketStats {
+  pub msgs_sent: i64,
+  pub bytes_sent: i64,
+  pub msgs_received: i64,
+  pub bytes_received: i64,
+  /**
+   * The number of writes that failed with EAGAIN or EWOULDBLOCK and were
+   * retried later.
+   */
+  pub eagain_retries: i64,
+  /**
+   * The number of writes that failed with ENOBUFS and were retried later.
+   */
+  pub enobufs_retries: i64,
+  /**
+   * Messages that were never sent because of errors or the socket closed.
+   */
+  pub msgs_dropped: i64,
+  /**
+   * Received messages that were truncated because the read buffer is not
+   * large enough.
+   */
+  pub msgs_truncated: i64,
+  /**
+   * The number of messages waiting to be sent.
+   */
+  pub queue_length: u32,
+  /**
+   * The bytes of messages waiting to be sent.
+   */
+  pub queue_bytes: i64,
+  /**
+   * The sum of the time from enqueuing to sending of sent messages in
+   * milliseconds. Divide it by `msgsSent` to get the average.
+   */
+  pub send_latency_total: f64,
+  /**
+   * The max time from enqueuing to sending of sent messages in milliseconds.
+   */
+  pub send_latency_max: f64,
+}
+
+/**
+ * Counters updated in the hot paths of sockets. Everything is a plain integer
+ * so that a snapshot is cheap.
+ */
+#[derive(Default)]
+pub(crate) struct Counters {
+  msgs_sent: u64,
+  bytes_sent: u64,
+  msgs_received: u64,
+  bytes_received: u64,
+  eagain_retries: u64,
+  enobufs_retries: u64,
+  msgs_dropped: u64,
+  msgs_truncated: u64,
+  queue_length: usize,
+  queue_bytes: usize,
+  send_latency_total: Duration,
+  send_latency_max: Duration,
+}
+
+impl Counters {
+  pub fn enqueue(&mut self, bytes: usize) {
+    self.queue_length += 1;
+    self.queue_bytes += bytes;
+  }
+
+  /**
+   * Some bytes of the head message have been written.
+   */
+  pub fn written(&mut self, bytes: usize) {
+    self.bytes_sent += bytes as u64;
+    self.queue_bytes = self.queue_bytes.saturating_sub(bytes);
+  }
+
+  /**
+   * The head message has been written completely.
+   */
+  pub fn sent(&mut self, enqueued_at: Instant) {
+    let latency = enqueued_at.elapsed();
+    self.msgs_sent += 1;
+    self.queue_length = self.queue_length.saturating_sub(1);
+    self.send_latency_total += latency;
+    if latency > self.send_latency_max {
+      self.send_latency_max = latency;
+    }
+  }
+
+  /**
+   * A message with `bytes` left unsent has been removed from the queue.
+   */
+  pub fn dropped(&mut self, bytes: usize) {
+    self.msgs_dropped += 1;
+    self.queue_length = self.queue_length.saturating_sub(1);
+    self.queue_bytes = self.queue_bytes.saturating_sub(bytes);
+  }
+
+  /**
+   * A write failed with `err` and will be retried.
+   */
+  pub fn retry(&mut self, err: i32) {
+    if err == EAGAIN || err == EWOULDBLOCK {
+      self.eagain_retries += 1;
+    } else if err == ENOBUFS {
+      self.enobufs_retries += 1;
+    }
+  }
+
+  pub fn received(&mut self, bytes: usize, truncated: bool) {
+    self.msgs_received += 1;
+    self.bytes_received += bytes as u64;
+    if truncated {
+      self.msgs_truncated += 1;
+    }
+  }
+
+  pub fn snapshot(&self) -> SocketStats {
+    SocketStats {
+      msgs_sent: self.msgs_sent as i64,
+      bytes_sent: self.bytes_sent as i64,
+      msgs_received: self.msgs_received as i64,
+      bytes_received: self.bytes_received as i64,
+      eagain_retries: self.eagain_retries as i64,
+      enobufs_retries: self.enobufs_retries as i64,
+      msgs_dropped: self.msgs_dropped as i64,
+      msgs_truncated: self.msgs_truncated as i64,
+      queue_length: self.queue_length as u32,
+      queue_bytes: self.queue_bytes as i64,
+      send_latency_total: self.send_latency_total.as_secs_f64() * 1000.0,
+      send_latency_max: self.send_latency_max.as_secs_f64() * 1000.0,
+    }
+  }
+}
@@ -0,0 +1,93 @@
+use std::mem;
+use std::ptr;
+
+use libc::{msghdr, timespec};
+use napi::{Env, JsUnknown, Result};
+
+#[cfg(not(target_os = "linux"))]
+use crate::util::error;
+
+/**
+ * Enable SO_TIMESTAMPNS so that the kernel reports the time when each
+ * message is received, which isn't skewed by the delay of the event loop.
+ */
+#[cfg(target_os = "linux")]
+pub(crate) fn set_recv_timestamp(fd: i32, enabled: bool) -> Result<()> {
+  crate::socket::set_int_sockopt(fd, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, enabled as i32)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_recv_timestamp(_fd: i32, _enabled: bool) -> Result<()> {
+  Err(error(
+    "SO_TIMESTAMPNS is not supported on this platform".to_string(),
+  ))
+}
+
+// CMSG_SPACE() isn't a const fn so that the sizes are rounded up to u64,
+// which is at least the alignment of control messages.
+const CMSG_BUF_LEN: usize =
+  mem::size_of::<libc::cmsghdr>().div_ceil(8) + mem::size_of::<timespec>().div_ceil(8);
+
+/**
+ * A buffer of control messages that fits a timestamp. It's made of u64 to
+ * keep the alignment of cmsghdr and lives on the stack so that reading
+ * doesn't allocate for it.
+ */
+pub(crate) struct CmsgBuf([u64; CMSG_BUF_LEN]);
+
+impl CmsgBuf {
+  pub fn new() -> Self {
+    debug_assert!(
+      unsafe { libc::CMSG_SPACE(mem::size_of::<timespec>() as u32) } as usize
+        <= CMSG_BUF_LEN * mem::size_of::<u64>()
+    );
+    CmsgBuf([0; CMSG_BUF_LEN])
+  }
+
+  pub fn attach(&mut self, msg: &mut msghdr) {
+    msg.msg_control = self.0.as_mut_ptr() as *mut _;
+    msg.msg_controllen = mem::size_of_val(&self.0) as _;
+  }
+}
+
+/**
+ * Find the timestamp of SCM_TIMESTAMPNS in the control messages returned by
+ * recvmsg().
+ */
+#[cfg(target_os = "linux")]
+pub(crate) fn parse_recv_timestamp(msg: &msghdr) -> Option<timespec> {
+  unsafe {
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+      if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS {
+        return Some(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const timespec));
+      }
+      cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+  }
+
+  None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn parse_recv_timestamp(_msg: &msghdr) -> Option<timespec> {
+  None
+}
+
+/**
+ * `[seconds, nanoseconds]` since the Unix epoch like `process.hrtime()`, or
+ * null if the message doesn't come with a timestamp.
+ */
+// time_t and c_long aren't i64 on every platform
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn timestamp_to_js(env: Env, ts: Option<timespec>) -> Result<JsUnknown> {
+  let ts = match ts {
+    Some(ts) => ts,
+    None => return Ok(env.get_null()?.into_unknown()),
+  };
+
+  let mut arr = env.create_array_with_length(2)?;
+  arr.set_element(0, env.create_int64(ts.tv_sec as i64)?)?;
+  arr.set_element(1, env.create_int64(ts.tv_nsec as i64)?)?;
+  Ok(arr.into_unknown())
+}
@@ -1,22 +1,22 @@
 use std::ffi::CStr;
-use std::intrinsics::transmute;
-use std::mem;
+use std::mem::{self, transmute};
+use std::ptr;
+use std::slice;
 
-use libc::{c_char, sockaddr, sockaddr_un};
-use napi::{self, Error, JsBuffer, JsFunction, JsObject, Result};
+use libc::{c_char, iovec, sockaddr, sockaddr_un};
+use napi::{self, Env, Error, JsBuffer, JsFunction, JsObject, NapiRaw, Result};
 use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use uv_sys::sys;
 
-pub(crate) fn i8_slice_into_u8_slice<'a>(slice: &'a [i8]) -> &'a [u8] {
+pub(crate) fn i8_slice_into_u8_slice(slice: &[i8]) -> &[u8] {
   unsafe { &*(slice as *const [i8] as *const [u8]) }
 }
 
 pub(crate) fn addr_to_string(addr: &sockaddr_un) -> String {
   // sockaddr_un.sun_path/c_char has varied types in different operating systems
-  // so that we directly transmute() it
-  let path_ref: &[i8] = unsafe { transmute(&addr.sun_path as &[c_char]) };
-  let sockname = i8_slice_into_u8_slice(path_ref);
+  // so that we directly cast it
+  let sockname = unsafe { &*(&addr.sun_path as &[c_char] as *const [c_char] as *const [u8]) };
   let sockname = unsafe { str_from_u8_nul_utf8_unchecked(sockname) };
 
   sockname.to_string()
@@ -46,17 +46,10 @@ pub(crate) fn nix_err(err: Errno) -> Error {
 }
 
 pub(crate) fn uv_err_msg(errno: i32) -> String {
-  let msg = unsafe {
+  unsafe {
     let ret = sys::uv_err_name(errno);
-    let ret = CStr::from_ptr(ret);
-    ret
-      .to_str()
-      .map_err(|_| error("parsing cstr failed".to_string()))
-      .unwrap()
-      .to_string()
-  };
-
-  msg
+    CStr::from_ptr(ret).to_string_lossy().into_owned()
+  }
 }
 
 pub(crate) fn uv_err(errno: i32) -> napi::Error {
@@ -77,6 +70,37 @@ pub(crate) fn get_err() -> Error {
   error(err.desc().to_string())
 }
 
+/**
+ * Create a js error of `errno` with its name as the `code`, e.g. `'ECANCELED'`.
+ */
+pub(crate) fn create_errno_error(env: Env, errno: i32) -> Result<JsObject> {
+  create_errno_error_with_msg(env, errno, Errno::from_i32(errno).desc())
+}
+
+/**
+ * Like `create_errno_error()` but with a message that tells more than the
+ * description of `errno`.
+ */
+pub(crate) fn create_errno_error_with_msg(env: Env, errno: i32, msg: &str) -> Result<JsObject> {
+  let err = Errno::from_i32(errno);
+  let mut obj = env.create_error(error(msg.to_string()))?;
+  obj.set_named_property("code", env.create_string(&format!("{:?}", err))?)?;
+  Ok(obj)
+}
+
+/**
+ * Throw a js error of `errno`, which keeps its `code` unlike returning a
+ * `napi::Error`. The returned error tells napi-rs that an exception is
+ * pending.
+ */
+pub(crate) fn throw_errno_error(env: Env, errno: i32, msg: &str) -> napi::Error {
+  let ret = create_errno_error_with_msg(env, errno, msg).and_then(|err| env.throw(err));
+  match ret {
+    Ok(()) => napi::Error::from_status(napi::Status::PendingException),
+    Err(e) => e,
+  }
+}
+
 pub(crate) fn resolve_libc_err(ret: i32) -> napi::Result<i32> {
   if ret != -1 {
     return Ok(ret);
@@ -86,8 +110,8 @@ pub(crate) fn resolve_libc_err(ret: i32) -> napi::Result<i32> {
 }
 
 #[allow(dead_code)]
-pub(crate) unsafe fn extend_life<'a, T>(e: &'a T) -> &'static T {
-  transmute(e)
+pub(crate) unsafe fn extend_life<T>(e: &T) -> &'static T {
+  transmute::<&T, &'static T>(e)
 }
 
 pub(crate) unsafe fn str_from_u8_nul_utf8_unchecked(utf8_src: &[u8]) -> &str {
@@ -99,7 +123,7 @@ pub(crate) unsafe fn str_from_u8_nul_utf8_unchecked(utf8_src: &[u8]) -> &str {
     }
     nul_range_end += 1;
   }
-  return ::std::str::from_utf8_unchecked(&utf8_src[0..nul_range_end]);
+  ::std::str::from_utf8_unchecked(&utf8_src[0..nul_range_end])
 }
 
 pub(crate) fn set_non_block(fd: i32) -> Result<()> {
@@ -112,13 +136,96 @@ pub(crate) fn set_clo_exec(fd: i32) -> Result<()> {
   Ok(())
 }
 
-pub(crate) fn buf_into_vec(buf: JsBuffer, offset: i32, length: i32) -> Result<Vec<u8>> {
-  let buf = buf.into_value()?;
-  let end = offset + length;
+/**
+ * The data of a js buffer. It doesn't use `JsBuffer::into_value()`, which
+ * panics on empty buffers whose data is null.
+ */
+pub(crate) fn buf_data(env: Env, buf: &JsBuffer) -> Result<&[u8]> {
+  let mut data = ptr::null_mut();
+  let mut len = 0;
+  let status =
+    unsafe { napi::sys::napi_get_buffer_info(env.raw(), buf.raw(), &mut data, &mut len) };
+  if status != napi::sys::Status::napi_ok {
+    return Err(Error::from_status(napi::Status::from(status)));
+  }
+
+  if len == 0 {
+    return Ok(&[]);
+  }
+  Ok(unsafe { slice::from_raw_parts(data as *const u8, len) })
+}
+
+/**
+ * Copy `length` bytes of a js buffer from `offset`.
+ */
+pub(crate) fn buf_into_vec(env: Env, buf: JsBuffer, offset: i32, length: i32) -> Result<Vec<u8>> {
+  let buf = buf_data(env, &buf)?;
+  let len = buf.len();
+  if offset < 0 || length < 0 || offset as usize + length as usize > len {
+    return Err(error(format!(
+      "offset {} and length {} are out of the buffer of {} bytes",
+      offset, length, len
+    )));
+  }
+
   let offset = offset as usize;
-  let end = end as usize;
+  Ok(buf[offset..offset + length as usize].to_vec())
+}
+
+/**
+ * IOV_MAX is 1024 on both Linux and MacOS.
+ */
+const MAX_MSG_BUFS: usize = 1024;
+
+/**
+ * The buffers of a message, which are sent together as one message with
+ * sendmsg() and an iovec for each of them.
+ */
+pub(crate) struct MsgBufs(Vec<Vec<u8>>);
+
+impl MsgBufs {
+  /**
+   * Copy js buffers without concatenating them.
+   */
+  pub(crate) fn from_js(env: Env, bufs: Vec<JsBuffer>) -> Result<Self> {
+    if bufs.len() > MAX_MSG_BUFS {
+      return Err(error(format!(
+        "a message can't have more than {} buffers",
+        MAX_MSG_BUFS
+      )));
+    }
+
+    let bufs = bufs
+      .iter()
+      .map(|buf| Ok(buf_data(env, buf)?.to_vec()))
+      .collect::<Result<Vec<_>>>()?;
+    Ok(MsgBufs(bufs))
+  }
+
+  pub(crate) fn len(&self) -> usize {
+    self.0.iter().map(|buf| buf.len()).sum()
+  }
 
-  Ok(buf[offset..end].to_vec())
+  /**
+   * The iovecs pointing to the buffers. They are valid until the buffers are
+   * dropped.
+   */
+  pub(crate) fn iovecs(&mut self) -> Vec<iovec> {
+    self
+      .0
+      .iter_mut()
+      .map(|buf| iovec {
+        iov_base: buf.as_mut_ptr() as *mut _,
+        iov_len: buf.len(),
+      })
+      .collect()
+  }
+}
+
+impl From<Vec<u8>> for MsgBufs {
+  fn from(buf: Vec<u8>) -> Self {
+    MsgBufs(vec![buf])
+  }
 }
 
 pub(crate) fn check_emit(ee: &JsObject) -> Result<()> {
@@ -1,87 +1,208 @@
 use std::{
-  collections::HashSet,
+  collections::HashMap,
   sync::{Mutex, MutexGuard, OnceLock},
 };
 
 use crate::util;
 use napi::{Env, Result};
-use uv_sys::sys::{uv_close, uv_handle_t};
+use uv_sys::sys::{uv_close, uv_handle_t, uv_is_closing};
 
-// UV_HANDLES should only be used in clean up hooks
-static mut UV_HANDLES: OnceLock<Mutex<HashSet<*mut uv_handle_t>>> = OnceLock::new();
-static HOOK_INITED: OnceLock<bool> = OnceLock::new();
+/**
+ * A socket that should be released when its environment is torn down.
+ */
+pub(crate) trait Teardown {
+  /**
+   * Close the fd and release everything of the socket. It's called in the
+   * clean up hook so that js can't be called.
+   */
+  fn teardown(&self);
+}
+
+struct Owner(*const dyn Teardown);
+
+// Owners are only touched in the thread of their environment.
+unsafe impl Send for Owner {}
+
+type Handles = HashMap<usize, Option<Owner>>;
+
+/**
+ * What we keep for each Node.js environment. It's dropped in the clean up
+ * hook of the environment.
+ */
+#[derive(Default)]
+struct EnvState {
+  handles: Handles,
+  /**
+   * Whether sockets that get garbage-collected without being closed print a
+   * warning, see `set_unclosed_warning()`.
+   */
+  warn_unclosed: bool,
+}
 
-fn get_handles<T>(f: T) -> Result<()>
+// UV_HANDLES keeps the handles of every Node.js environment, e.g. the main
+// thread and workers, and the sockets owning them so that each environment
+// only releases its own sockets in its clean up hook. Pointers are stored as
+// usize so that the map is Send.
+static UV_HANDLES: OnceLock<Mutex<HashMap<usize, EnvState>>> = OnceLock::new();
+
+fn get_handles<T, R>(f: T) -> Result<R>
 where
-  T: FnOnce(MutexGuard<'_, HashSet<*mut uv_handle_t>>) -> Result<()>,
+  T: FnOnce(MutexGuard<'_, HashMap<usize, EnvState>>) -> Result<R>,
 {
-  let handles = unsafe { UV_HANDLES.get_or_init(|| Mutex::new(HashSet::new())) };
+  let handles = UV_HANDLES.get_or_init(|| Mutex::new(HashMap::new()));
+  let inner = handles.lock().map_err(util::error)?;
+
+  f(inner)
+}
+
+fn env_key(env: &Env) -> usize {
+  env.raw() as usize
+}
 
-  {
-    let inner = handles.lock();
-    if inner.is_err() {
-      let e = inner.err().unwrap();
-      return Err(util::error(e));
+/**
+ * Create the handle set of `env` and register a clean up hook for it if it
+ * doesn't exist.
+ */
+fn ensure_env(env: &mut Env) -> Result<()> {
+  let key = env_key(env);
+  let created = get_handles(|mut inner| {
+    if inner.contains_key(&key) {
+      return Ok(false);
+    }
+    inner.insert(key, EnvState::default());
+    Ok(true)
+  })?;
+
+  if created {
+    let ret = env.add_env_cleanup_hook(key, |key| {
+      if let Err(e) = cleanup_handles(key) {
+        println!("cleanup_handles failed, msg: {}", e)
+      }
+    });
+    if let Err(e) = ret {
+      get_handles(|mut inner| {
+        inner.remove(&key);
+        Ok(())
+      })?;
+      return Err(e);
     }
-    let inner = inner.unwrap();
-    f(inner)?
   }
 
   Ok(())
 }
 
-pub(crate) fn insert_handle(handle: *mut uv_handle_t) -> Result<()> {
+pub(crate) fn insert_handle(env: Env, handle: *mut uv_handle_t) -> Result<()> {
+  let mut env = env;
+  ensure_env(&mut env)?;
+
+  let key = env_key(&env);
   get_handles(|mut inner| {
-    inner.insert(handle);
+    if let Some(state) = inner.get_mut(&key) {
+      state.handles.insert(handle as usize, None);
+    }
     Ok(())
   })
 }
 
-// TODO pointer same?
-pub(crate) fn remove_handle(handle: *mut uv_handle_t) -> Result<()> {
+/**
+ * Set the socket owning `handle`. The socket should call remove_handle()
+ * before it gets dropped.
+ */
+pub(crate) fn set_owner(
+  env: Env,
+  handle: *mut uv_handle_t,
+  owner: *const dyn Teardown,
+) -> Result<()> {
+  let key = env_key(&env);
   get_handles(|mut inner| {
-    inner.remove(&handle);
+    if let Some(owner_ref) = inner
+      .get_mut(&key)
+      .and_then(|state| state.handles.get_mut(&(handle as usize)))
+    {
+      *owner_ref = Some(Owner(owner));
+    }
     Ok(())
   })
 }
 
-pub(crate) fn cleanup_handles() -> Result<()> {
-  let handles = unsafe { UV_HANDLES.get() };
-  if handles.is_none() {
-    return Ok(());
-  }
-
+pub(crate) fn remove_handle(env: Env, handle: *mut uv_handle_t) -> Result<()> {
+  let key = env_key(&env);
   get_handles(|mut inner| {
-    for handle in inner.drain() {
-      if handle.is_null() {
-        continue;
-      }
-      unsafe {
-        uv_close(handle, None);
-      };
+    if let Some(state) = inner.get_mut(&key) {
+      state.handles.remove(&(handle as usize));
     }
+    Ok(())
+  })
+}
 
+/**
+ * Enable or disable the warning of unclosed sockets in `env` only, e.g. in
+ * a worker but not the main thread.
+ */
+pub(crate) fn set_unclosed_warning(env: Env, enabled: bool) -> Result<()> {
+  let mut env = env;
+  ensure_env(&mut env)?;
+
+  let key = env_key(&env);
+  get_handles(|mut inner| {
+    if let Some(state) = inner.get_mut(&key) {
+      state.warn_unclosed = enabled;
+    }
     Ok(())
   })
 }
 
-#[napi]
-#[allow(dead_code)]
-pub fn init_cleanup_hook(mut env: Env) -> Result<()> {
-  if HOOK_INITED.get().is_some() {
-    return Ok(());
-  }
-  HOOK_INITED.get_or_init(|| true);
-
-  env.add_env_cleanup_hook((), move |_| {
-    let result = cleanup_handles();
-    if result.is_err() {
-      println!(
-        "cleanup_handles failed, msg: {}",
-        result.err().unwrap().to_string()
-      )
+/**
+ * Whether sockets of `env` warn when they get garbage-collected without
+ * being closed. It's false once the environment is torn down.
+ */
+pub(crate) fn unclosed_warning(env: Env) -> bool {
+  let key = env_key(&env);
+  get_handles(|inner| Ok(inner.get(&key).map(|state| state.warn_unclosed)))
+    .ok()
+    .flatten()
+    .unwrap_or(false)
+}
+
+/**
+ * Release the sockets of the environment identified by `key`. It's called
+ * when the environment is being torn down, e.g. a worker exits or gets
+ * terminated, and its loop is still alive.
+ */
+fn cleanup_handles(key: usize) -> Result<()> {
+  // the lock is released before sockets remove their handles
+  let state = get_handles(|mut inner| Ok(inner.remove(&key)))?;
+  let handles = match state {
+    Some(state) => state.handles,
+    None => return Ok(()),
+  };
+
+  for (handle, owner) in handles {
+    let handle = handle as *mut uv_handle_t;
+    if handle.is_null() {
+      continue;
     }
-  })?;
+    match owner {
+      Some(Owner(owner)) => unsafe { (*owner).teardown() },
+      None => unsafe {
+        // the fd is closed when the socket gets dropped
+        if uv_is_closing(handle) == 0 {
+          uv_close(handle, None);
+        }
+      },
+    }
+  }
 
   Ok(())
 }
+
+/**
+ * Register the clean up hook of the current environment. Hooks are
+ * registered automatically once sockets get created so that calling it is
+ * optional.
+ */
+#[napi]
+#[allow(dead_code)]
+pub fn init_cleanup_hook(mut env: Env) -> Result<()> {
+  ensure_env(&mut env)
+}